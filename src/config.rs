@@ -1,25 +1,93 @@
+use reqwest::tls::Version;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::net::IpAddr;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
 	pub unifi: UnifiConfig,
 	pub monitoring: MonitoringConfig,
 	pub server: ServerConfig,
+	#[serde(default)]
+	pub metrics: MetricsConfig,
 	pub logging: LoggingConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct UnifiConfig {
 	pub ip: String,
+	#[serde(default)]
 	pub api_token: String,
+	pub api_token_keyring: Option<KeyringEntry>,
 	pub poll_interval: u64,
+	#[serde(default = "default_discovery_interval")]
+	pub discovery_interval: u64,
+	#[serde(default = "default_request_retries")]
+	pub request_retries: u32,
+	#[serde(default = "default_max_concurrent_requests")]
+	pub max_concurrent_requests: usize,
+	#[serde(default)]
+	pub verify_tls: bool,
+	pub ca_cert_path: Option<String>,
+	pub min_tls_version: Option<String>,
+	pub local_address: Option<String>,
+}
+
+// OS keyring entry holding the API token, used instead of api_token when set
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyringEntry {
+	pub service: String,
+	pub account: String,
+}
+
+fn default_discovery_interval() -> u64 {
+	600
+}
+
+fn default_request_retries() -> u32 {
+	2
+}
+
+fn default_max_concurrent_requests() -> usize {
+	8
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MonitoringConfig {
 	pub network_devices: bool,
 	pub protect_sensors: bool,
+	#[serde(default)]
+	pub protect_system: bool,
+	#[serde(default)]
+	pub critical_devices: Vec<String>,
+	#[serde(default)]
+	pub sensor_metadata: HashMap<String, BTreeMap<String, String>>,
+	#[serde(default)]
+	pub sensor_missing_behavior: SensorMissingBehavior,
+	#[serde(default)]
+	pub radio_aggregation: RadioAggregation,
+	pub max_data_age_secs: Option<u64>,
+	pub metric_ttl_seconds: Option<u64>,
+}
+
+// What happens to a previously seen sensor that is absent from a successful poll
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorMissingBehavior {
+	Keep,
+	Prune,
+	#[default]
+	MarkDisconnected,
+}
+
+// Granularity of per-radio WiFi metrics such as tx retries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RadioAggregation {
+	#[default]
+	PerRadio,
+	PerDevice,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -27,6 +95,87 @@ pub struct ServerConfig {
 	pub bind_address: String,
 	pub port: u16,
 	pub bearer_token: Option<String>,
+	pub network_bearer_token: Option<String>,
+	pub protect_bearer_token: Option<String>,
+	pub instance_label: Option<String>,
+	pub group_label: Option<String>,
+	#[serde(default = "default_group_label_default")]
+	pub group_label_default: String,
+	#[serde(default = "default_tcp_backlog")]
+	pub tcp_backlog: u32,
+	#[serde(default)]
+	pub standby: bool,
+}
+
+fn default_group_label_default() -> String {
+	"none".to_string()
+}
+
+fn default_tcp_backlog() -> u32 {
+	1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+	#[serde(default)]
+	pub ip_address_fallback: IpAddressFallback,
+	#[serde(default)]
+	pub value_scale: ValueScale,
+	pub max_label_length: Option<usize>,
+	#[serde(default = "default_poll_duration_buckets")]
+	pub poll_duration_buckets: Vec<f64>,
+	#[serde(default)]
+	pub non_finite_values: NonFiniteValues,
+	#[serde(default)]
+	pub emit_null_as_stale: bool,
+	#[serde(default)]
+	pub help_overrides: HashMap<String, String>,
+}
+
+pub fn default_poll_duration_buckets() -> Vec<f64> {
+	vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+}
+
+impl Default for MetricsConfig {
+	fn default() -> Self {
+		MetricsConfig {
+			ip_address_fallback: IpAddressFallback::default(),
+			value_scale: ValueScale::default(),
+			max_label_length: None,
+			poll_duration_buckets: default_poll_duration_buckets(),
+			non_finite_values: NonFiniteValues::default(),
+			emit_null_as_stale: false,
+			help_overrides: HashMap::new(),
+		}
+	}
+}
+
+// Value used for the ip_address label when a device reports no IP
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpAddressFallback {
+	#[default]
+	Unknown,
+	Mac,
+	Omit,
+}
+
+// Scale used for CPU, memory, humidity, battery and PoE utilization metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueScale {
+	#[default]
+	Ratio,
+	Percent,
+}
+
+// Handling of NaN and infinite sample values reported by the API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFiniteValues {
+	#[default]
+	Drop,
+	Clamp,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -35,12 +184,172 @@ pub struct LoggingConfig {
 	pub log_level: String,
 }
 
+// Labels already emitted on sensor metrics that metadata must not override
+const RESERVED_SENSOR_LABELS: &[&str] = &[
+	"sensor_id",
+	"sensor_name",
+	"mount_type",
+	"state_raw",
+	"instance",
+];
+
+fn is_valid_label_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+		&& !name.starts_with("__")
+}
+
+#[cfg(feature = "keyring")]
+fn read_keyring_token(entry: &KeyringEntry) -> Result<String, Box<dyn std::error::Error>> {
+	keyring::Entry::new(&entry.service, &entry.account)
+		.and_then(|e| e.get_password())
+		.map_err(|e| {
+			format!(
+				"Failed to read unifi.api_token_keyring (service '{}', account '{}'): {}",
+				entry.service, entry.account, e
+			)
+			.into()
+		})
+}
+
+#[cfg(not(feature = "keyring"))]
+fn read_keyring_token(entry: &KeyringEntry) -> Result<String, Box<dyn std::error::Error>> {
+	Err(
+		format!(
+			"Cannot read unifi.api_token_keyring (service '{}', account '{}'): unifimetrics was built without the \"keyring\" feature",
+			entry.service, entry.account
+		)
+		.into(),
+	)
+}
+
+// The rustls backend only implements TLS 1.2 and 1.3
+pub fn parse_tls_version(version: &str) -> Result<Version, String> {
+	match version.trim() {
+		"1.2" => Ok(Version::TLS_1_2),
+		"1.3" => Ok(Version::TLS_1_3),
+		"1.0" | "1.1" => Err(format!(
+			"unifi.min_tls_version '{}' is not supported, the minimum available version is 1.2",
+			version
+		)),
+		_ => Err(format!(
+			"unifi.min_tls_version '{}' is invalid, expected \"1.2\" or \"1.3\"",
+			version
+		)),
+	}
+}
+
 impl Config {
 	pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
 		let config_str = fs::read_to_string(path)?;
-		let config: Config = toml::from_str(&config_str)?;
+		let mut config: Config = toml::from_str(&config_str)?;
+		// Validate before the keyring fills in api_token, so the token source check sees the file
+		config.validate()?;
+		if let Some(ref entry) = config.unifi.api_token_keyring {
+			config.unifi.api_token = read_keyring_token(entry)?;
+		}
 		Ok(config)
 	}
+
+	fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+		match (
+			self.unifi.api_token.is_empty(),
+			self.unifi.api_token_keyring.is_some(),
+		) {
+			(true, false) => {
+				return Err("unifi.api_token or unifi.api_token_keyring must be set".into());
+			}
+			(false, true) => {
+				return Err("unifi.api_token and unifi.api_token_keyring cannot both be set".into());
+			}
+			_ => {}
+		}
+
+		// Without a global token /metrics is public and serves what the subsystem tokens protect
+		if self.server.bearer_token.is_none() {
+			for (name, token) in [
+				("network_bearer_token", &self.server.network_bearer_token),
+				("protect_bearer_token", &self.server.protect_bearer_token),
+			] {
+				if token.is_some() {
+					return Err(format!(
+						"server.{} requires server.bearer_token, otherwise /metrics serves every metric without authentication",
+						name
+					)
+					.into());
+				}
+			}
+		}
+
+		// Without verification any certificate is accepted, so a trusted CA would silently do nothing
+		if self.unifi.ca_cert_path.is_some() && !self.unifi.verify_tls {
+			return Err("unifi.ca_cert_path requires unifi.verify_tls = true".into());
+		}
+
+		if let Some(ref version) = self.unifi.min_tls_version {
+			parse_tls_version(version)?;
+		}
+
+		if let Some(ref address) = self.unifi.local_address
+			&& address.parse::<IpAddr>().is_err()
+		{
+			return Err(
+				format!(
+					"unifi.local_address must be an IPv4 or IPv6 address, got '{}'",
+					address
+				)
+				.into(),
+			);
+		}
+
+		if let Some(ref instance) = self.server.instance_label
+			&& (instance.trim().is_empty() || instance.chars().any(char::is_control))
+		{
+			return Err(
+				"server.instance_label must be a non-empty string without control characters".into(),
+			);
+		}
+
+		if let Some(ref group) = self.server.group_label
+			&& (!is_valid_label_name(group) || group == "instance")
+		{
+			return Err(
+				format!(
+					"server.group_label '{}' is not a valid label name or is reserved",
+					group
+				)
+				.into(),
+			);
+		}
+
+		let buckets = &self.metrics.poll_duration_buckets;
+		if buckets.is_empty()
+			|| buckets.iter().any(|b| !b.is_finite() || *b <= 0.0)
+			|| buckets.windows(2).any(|w| w[0] >= w[1])
+		{
+			return Err(
+				"metrics.poll_duration_buckets must be a non-empty list of positive, strictly increasing values"
+					.into(),
+			);
+		}
+
+		for (sensor_id, labels) in &self.monitoring.sensor_metadata {
+			for name in labels.keys() {
+				if !is_valid_label_name(name) || RESERVED_SENSOR_LABELS.contains(&name.as_str()) {
+					return Err(
+						format!(
+							"monitoring.sensor_metadata.{}: invalid or reserved label name '{}'",
+							sensor_id, name
+						)
+						.into(),
+					);
+				}
+			}
+		}
+
+		Ok(())
+	}
 }
 
 impl Default for Config {
@@ -49,17 +358,40 @@ impl Default for Config {
 			unifi: UnifiConfig {
 				ip: "10.0.0.1".to_string(),
 				api_token: "".to_string(),
+				api_token_keyring: None,
 				poll_interval: 30,
+				discovery_interval: default_discovery_interval(),
+				request_retries: default_request_retries(),
+				max_concurrent_requests: default_max_concurrent_requests(),
+				verify_tls: false,
+				ca_cert_path: None,
+				min_tls_version: None,
+				local_address: None,
 			},
 			monitoring: MonitoringConfig {
 				network_devices: true,
 				protect_sensors: true,
+				protect_system: false,
+				critical_devices: Vec::new(),
+				sensor_metadata: HashMap::new(),
+				sensor_missing_behavior: SensorMissingBehavior::default(),
+				radio_aggregation: RadioAggregation::default(),
+				max_data_age_secs: None,
+				metric_ttl_seconds: None,
 			},
 			server: ServerConfig {
 				bind_address: "0.0.0.0".to_string(),
 				port: 9090,
 				bearer_token: None,
+				network_bearer_token: None,
+				protect_bearer_token: None,
+				instance_label: None,
+				group_label: None,
+				group_label_default: default_group_label_default(),
+				tcp_backlog: default_tcp_backlog(),
+				standby: false,
 			},
+			metrics: MetricsConfig::default(),
 			logging: LoggingConfig {
 				log_file: None,
 				log_level: "info".to_string(),
@@ -67,3 +399,57 @@ impl Default for Config {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn valid_config() -> Config {
+		let mut config = Config::default();
+		config.unifi.api_token = "token".to_string();
+		config
+	}
+
+	#[test]
+	fn exactly_one_api_token_source_is_required() {
+		let mut config = valid_config();
+		assert!(config.validate().is_ok());
+
+		config.unifi.api_token_keyring = Some(KeyringEntry {
+			service: "unifimetrics".to_string(),
+			account: "unifi".to_string(),
+		});
+		assert!(config.validate().is_err());
+
+		config.unifi.api_token.clear();
+		assert!(config.validate().is_ok());
+
+		config.unifi.api_token_keyring = None;
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn subsystem_tokens_require_global_token() {
+		let mut config = valid_config();
+		config.server.network_bearer_token = Some("network-team-token".to_string());
+		assert!(config.validate().is_err());
+
+		config.server.bearer_token = Some("global-token".to_string());
+		assert!(config.validate().is_ok());
+
+		config.server.bearer_token = None;
+		config.server.network_bearer_token = None;
+		config.server.protect_bearer_token = Some("security-team-token".to_string());
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn ca_cert_path_requires_verify_tls() {
+		let mut config = valid_config();
+		config.unifi.ca_cert_path = Some("/etc/unifimetrics/ca.pem".to_string());
+		assert!(config.validate().is_err());
+
+		config.unifi.verify_tls = true;
+		assert!(config.validate().is_ok());
+	}
+}
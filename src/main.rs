@@ -4,21 +4,76 @@ mod metrics;
 mod unifi;
 
 use anyhow::Result;
-use axum::{Router, routing::get};
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{
+	Router,
+	routing::{get, post},
+};
 use std::sync::Arc;
-use tokio::time::{Duration, interval};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::{TcpListener, TcpSocket, lookup_host};
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{Duration, Instant, MissedTickBehavior, interval};
 
-use log::{error, info};
+use log::{error, info, warn};
 use std::env;
+use std::net::IpAddr;
 
-use crate::config::Config;
+use crate::config::{Config, MetricsConfig, parse_tls_version};
 use crate::logging::setup_logging;
-use crate::metrics::metrics_handler;
-use crate::unifi::{NetworkClient, ProtectClient, UnifiCache};
+use crate::metrics::{
+	evict_stale_metrics, is_authorized, is_available, is_known_family, metrics_handler,
+	network_metrics_handler, poll_status, protect_metrics_handler, record_poll_task_restart,
+	record_skipped_ticks, set_poll_duration_buckets,
+};
+use crate::unifi::{NetworkClient, ProtectClient, UnifiCache, dump_api};
 
 #[derive(Clone)]
 struct AppState {
 	bearer_token: Option<String>,
+	network_bearer_token: Option<String>,
+	protect_bearer_token: Option<String>,
+	instance_label: Option<String>,
+	group_label: Option<String>,
+	group_label_default: String,
+	metrics: MetricsConfig,
+	// Set while a warm standby instance waits to be promoted
+	standby: Arc<AtomicBool>,
+	// Enabled poll types checked by /health, with the subsystem each depends on
+	health_checks: Vec<(&'static str, &'static str)>,
+	poll_interval: Duration,
+	started: Instant,
+}
+
+impl AppState {
+	fn new(config: &Config) -> Self {
+		Self {
+			bearer_token: config.server.bearer_token.clone(),
+			network_bearer_token: config.server.network_bearer_token.clone(),
+			protect_bearer_token: config.server.protect_bearer_token.clone(),
+			instance_label: config.server.instance_label.clone(),
+			group_label: config.server.group_label.clone(),
+			group_label_default: config.server.group_label_default.clone(),
+			metrics: config.metrics.clone(),
+			standby: Arc::new(AtomicBool::new(config.server.standby)),
+			health_checks: [
+				(config.monitoring.network_devices, ("network", "network")),
+				(config.monitoring.protect_sensors, ("protect", "protect")),
+				(
+					config.monitoring.protect_system,
+					("protect_system", "protect"),
+				),
+			]
+			.into_iter()
+			.filter_map(|(enabled, check)| enabled.then_some(check))
+			.collect(),
+			poll_interval: Duration::from_secs(config.unifi.poll_interval),
+			started: Instant::now(),
+		}
+	}
 }
 
 #[tokio::main]
@@ -30,43 +85,80 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 		return Ok(());
 	}
 
-	let config_path = env::args()
-		.nth(1)
+	// Split out --dump-api <endpoint>, leaving the config path as the only positional argument
+	let mut dump_endpoint = None;
+	let mut positional = Vec::new();
+	let mut rest = args.iter().skip(1);
+	while let Some(arg) = rest.next() {
+		if arg == "--dump-api" {
+			let endpoint = rest
+				.next()
+				.ok_or("--dump-api requires an endpoint: sites | devices | sensors | statistics")?;
+			dump_endpoint = Some(endpoint.clone());
+		} else {
+			positional.push(arg.clone());
+		}
+	}
+
+	let config_path = positional
+		.into_iter()
+		.next()
 		.unwrap_or_else(|| "config.toml".to_string());
 
 	let config = Config::from_file(&config_path)?;
 
-	setup_logging(&config.logging)?;
+	// Keep stdout clean for JSON output when dumping API responses
+	if dump_endpoint.is_none() {
+		setup_logging(&config.logging)?;
 
-	info!("UnifiMetrics started with config: {}", config_path);
+		log_startup_banner(&config, &config_path);
+	}
 
-	info!(
-		"Metrics API enabled on port {} (format: OpenMetrics)",
-		config.server.port,
-	);
+	set_poll_duration_buckets(config.metrics.poll_duration_buckets.clone());
 
-	if config.server.bearer_token.is_some() {
-		info!("Bearer token authentication enabled for metrics endpoint");
+	// Create HTTP client, only validating certificates when verify_tls is enabled
+	let mut client_builder = reqwest::Client::builder()
+		.danger_accept_invalid_certs(!config.unifi.verify_tls)
+		.timeout(Duration::from_secs(5));
+
+	// Trust a private CA, failing at startup rather than on every request
+	if let Some(ref path) = config.unifi.ca_cert_path {
+		let pem = std::fs::read(path)
+			.map_err(|e| format!("Failed to read unifi.ca_cert_path '{}': {}", path, e))?;
+		let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+			.ok()
+			.filter(|certificates| !certificates.is_empty())
+			.ok_or_else(|| {
+				format!(
+					"No valid PEM certificates found in unifi.ca_cert_path '{}'",
+					path
+				)
+			})?;
+		for certificate in certificates {
+			client_builder = client_builder.add_root_certificate(certificate);
+		}
 	}
 
-	// Create HTTP client with certificate validation disabled
-	let client = reqwest::Client::builder()
-		.danger_accept_invalid_certs(true)
-		.timeout(Duration::from_secs(5))
-		.build()?;
+	if let Some(ref version) = config.unifi.min_tls_version {
+		client_builder = client_builder.min_tls_version(parse_tls_version(version)?);
+	}
+
+	// Egress API traffic from a specific source address on multi-homed hosts
+	if let Some(ref address) = config.unifi.local_address {
+		client_builder = client_builder.local_address(address.parse::<IpAddr>()?);
+	}
+
+	let client = client_builder.build()?;
 
 	// Create UniFi clients
-	let network_client = Arc::new(NetworkClient::new(
-		client.clone(),
-		config.unifi.ip.clone(),
-		config.unifi.api_token.clone(),
-	));
-
-	let protect_client = Arc::new(ProtectClient::new(
-		client.clone(),
-		config.unifi.ip.clone(),
-		config.unifi.api_token.clone(),
-	));
+	let network_client = Arc::new(NetworkClient::new(client.clone(), &config));
+
+	let protect_client = Arc::new(ProtectClient::new(client.clone(), &config));
+
+	if let Some(endpoint) = dump_endpoint {
+		dump_api(&network_client, &protect_client, &endpoint).await?;
+		return Ok(());
+	}
 
 	// Initialize cache
 	let cache = Arc::new(UnifiCache::new());
@@ -76,7 +168,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 		info!("Initializing network device monitoring");
 		match network_client.initialize_sites(&cache).await {
 			Ok(_) => info!("Network sites and devices loaded successfully"),
-			Err(e) => error!("Failed to initialize network monitoring: {}", e),
+			Err(e) => error!("Failed to initialize network monitoring: {:#}", e),
 		}
 	}
 
@@ -86,40 +178,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let poll_protect_client = protect_client.clone();
 	let poll_cache = cache.clone();
 
-	// Start background polling task
+	// Start background polling task, restarting it if it panics
 	tokio::spawn(async move {
-		let mut ticker = interval(Duration::from_secs(poll_config.unifi.poll_interval));
-		ticker.tick().await; // Skip first immediate tick
-
 		loop {
-			ticker.tick().await;
-
-			// Poll network devices
-			if poll_config.monitoring.network_devices {
-				info!("Polling network device statistics");
-				if let Err(e) = poll_network_client.poll_statistics(&poll_cache).await {
-					error!("Failed to poll network statistics: {}", e);
-				}
-			}
+			let task = tokio::spawn(run_polling(
+				poll_config.clone(),
+				poll_network_client.clone(),
+				poll_protect_client.clone(),
+				poll_cache.clone(),
+			));
 
-			// Poll protect sensors
-			if poll_config.monitoring.protect_sensors {
-				info!("Polling protect sensor data");
-				if let Err(e) = poll_protect_client.poll_sensors().await {
-					error!("Failed to poll protect sensors: {}", e);
+			match task.await {
+				Err(e) if e.is_panic() => {
+					error!("Polling task panicked, restarting: {}", e);
+					record_poll_task_restart();
 				}
+				_ => break,
 			}
 		}
 	});
 
-	let state = AppState {
-		bearer_token: config.server.bearer_token.clone(),
-	};
+	let state = AppState::new(&config);
+
+	#[cfg(unix)]
+	if config.server.standby {
+		let standby = state.standby.clone();
+		tokio::spawn(async move {
+			let mut promotion = match signal(SignalKind::user_defined1()) {
+				Ok(signal) => signal,
+				Err(e) => {
+					error!(
+						"Failed to listen for SIGUSR1, use POST /promote instead: {}",
+						e
+					);
+					return;
+				}
+			};
+			if promotion.recv().await.is_some() {
+				promote(&standby, "SIGUSR1");
+			}
+		});
+	}
 
 	// Build the application router
 	let app = Router::new()
 		.route("/metrics", get(metrics_handler))
+		.route("/metrics/network", get(network_metrics_handler))
+		.route("/metrics/protect", get(protect_metrics_handler))
 		.route("/health", get(health_handler))
+		.route("/promote", post(promote_handler))
 		.with_state(state);
 
 	// Start the server
@@ -128,18 +235,304 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 		config.server.bind_address, config.server.port
 	);
 
-	let listener = tokio::net::TcpListener::bind(format!(
-		"{}:{}",
-		config.server.bind_address, config.server.port
-	))
-	.await
-	.expect("Failed to bind to address");
+	let listener = bind_listener(&config)
+		.await
+		.expect("Failed to bind to address");
 
 	axum::serve(listener, app).await?;
 
 	Ok(())
 }
 
-async fn health_handler() -> &'static str {
-	"OK"
+// Bind with SO_REUSEADDR for smoother restarts and a configurable accept backlog
+async fn bind_listener(config: &Config) -> std::io::Result<TcpListener> {
+	let addr = lookup_host((config.server.bind_address.as_str(), config.server.port))
+		.await?
+		.next()
+		.ok_or_else(|| std::io::Error::other("Bind address did not resolve"))?;
+
+	let socket = if addr.is_ipv4() {
+		TcpSocket::new_v4()?
+	} else {
+		TcpSocket::new_v6()?
+	};
+	socket.set_reuseaddr(true)?;
+	socket.bind(addr)?;
+	socket.listen(config.server.tcp_backlog)
+}
+
+async fn run_polling(
+	config: Config,
+	network_client: Arc<NetworkClient>,
+	protect_client: Arc<ProtectClient>,
+	cache: Arc<UnifiCache>,
+) {
+	let period = Duration::from_secs(config.unifi.poll_interval);
+	let mut ticker = interval(period);
+	ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+	let mut last_tick = ticker.tick().await; // Skip first immediate tick
+	let discovery_interval = Duration::from_secs(config.unifi.discovery_interval);
+	let mut last_discovery = Instant::now(); // Discovery already ran at startup
+
+	loop {
+		let tick = ticker.tick().await;
+
+		// With MissedTickBehavior::Skip, overrunning polls show up as gaps between ticks
+		let elapsed_ticks = ((tick - last_tick).as_secs_f64() / period.as_secs_f64()).round() as u64;
+		let skipped = elapsed_ticks.saturating_sub(1);
+		if skipped > 0 {
+			warn!(
+				"Polling fell behind schedule, skipped {} tick(s); consider raising poll_interval",
+				skipped
+			);
+			record_skipped_ticks(skipped);
+		}
+		last_tick = tick;
+
+		// Periodically pick up newly adopted devices and sites, keeping the cache on failure,
+		// and retry a failed discovery on every tick
+		let discovery_due =
+			!discovery_interval.is_zero() && last_discovery.elapsed() >= discovery_interval;
+		if config.monitoring.network_devices && (discovery_due || network_client.discovery_failed()) {
+			info!("Re-discovering network sites and devices");
+			if let Err(e) = network_client.initialize_sites(&cache).await {
+				error!("Failed to re-discover network sites and devices: {:#}", e);
+			}
+			last_discovery = Instant::now();
+		}
+
+		// Poll network devices
+		if config.monitoring.network_devices {
+			info!("Polling network device statistics");
+			if let Err(e) = network_client.poll_statistics(&cache).await {
+				error!("Failed to poll network statistics: {:#}", e);
+			}
+		}
+
+		// Poll protect sensors
+		if config.monitoring.protect_sensors {
+			info!("Polling protect sensor data");
+			if let Err(e) = protect_client.poll_sensors().await {
+				error!("Failed to poll protect sensors: {:#}", e);
+			}
+		}
+
+		// Poll protect NVR system health
+		if config.monitoring.protect_system {
+			info!("Polling protect system data");
+			if let Err(e) = protect_client.poll_system().await {
+				error!("Failed to poll protect system: {:#}", e);
+			}
+		}
+
+		// Drop devices and sensors that haven't been refreshed within the TTL
+		if let Some(ttl) = config.monitoring.metric_ttl_seconds {
+			evict_stale_metrics(Duration::from_secs(ttl), Instant::now().into_std());
+		}
+	}
+}
+
+// Healthy while every enabled and installed subsystem's last poll succeeded and
+// a successful poll happened within two poll intervals (counted from startup at first)
+async fn health_handler(State(state): State<AppState>) -> Response {
+	let max_age = state.poll_interval * 2;
+	let mut problems = Vec::new();
+	let mut any_available = false;
+
+	for &(poll_type, subsystem) in &state.health_checks {
+		if !is_available(subsystem) {
+			continue;
+		}
+		any_available = true;
+
+		let status = poll_status(poll_type);
+		if let Some(ref status) = status
+			&& !status.success
+		{
+			problems.push(format!("{}: last poll failed", poll_type));
+			continue;
+		}
+
+		let last_success = status
+			.and_then(|status| status.last_success)
+			.unwrap_or(state.started.into_std());
+		if last_success.elapsed() > max_age {
+			problems.push(format!(
+				"{}: no successful poll in the last {}s",
+				poll_type,
+				max_age.as_secs()
+			));
+		}
+	}
+
+	// A wrong controller address or a proxy answering 404 for everything makes every
+	// subsystem look "not installed", which must not pass as healthy
+	if !state.health_checks.is_empty() && !any_available {
+		problems.push("no enabled subsystem is available".to_string());
+	}
+
+	if problems.is_empty() {
+		(StatusCode::OK, "OK").into_response()
+	} else {
+		(
+			StatusCode::SERVICE_UNAVAILABLE,
+			format!("UNHEALTHY\n{}\n", problems.join("\n")),
+		)
+			.into_response()
+	}
+}
+
+// Promote a standby instance so it starts serving metrics, guarded by the global bearer token
+async fn promote_handler(headers: HeaderMap, State(state): State<AppState>) -> Response {
+	if !is_authorized(&headers, state.bearer_token.as_deref()) {
+		return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+	}
+
+	promote(&state.standby, "POST /promote");
+	(StatusCode::OK, "Promoted").into_response()
+}
+
+fn promote(standby: &AtomicBool, trigger: &str) {
+	if standby.swap(false, Ordering::Relaxed) {
+		info!("Promoted from standby by {}, now serving metrics", trigger);
+	}
+}
+
+fn log_startup_banner(config: &Config, config_path: &str) {
+	let enabled = |flag: bool| if flag { "enabled" } else { "disabled" };
+	let api_token = if config.unifi.api_token.is_empty() {
+		"<not set>"
+	} else if config.unifi.api_token_keyring.is_some() {
+		"<redacted, from keyring>"
+	} else {
+		"<redacted>"
+	};
+
+	info!(
+		"{} v{} started with config: {}",
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION"),
+		config_path
+	);
+	info!(
+		"  Controller:       {} (api token: {})",
+		config.unifi.ip, api_token
+	);
+	info!(
+		"  TLS verification: {}{}",
+		enabled(config.unifi.verify_tls),
+		config
+			.unifi
+			.ca_cert_path
+			.as_deref()
+			.map(|path| format!(" (CA: {})", path))
+			.unwrap_or_default()
+	);
+	info!(
+		"  Min TLS version:  {}",
+		config.unifi.min_tls_version.as_deref().unwrap_or("default")
+	);
+	info!(
+		"  Local address:    {}",
+		config.unifi.local_address.as_deref().unwrap_or("default")
+	);
+	info!("  Poll interval:    {}s", config.unifi.poll_interval);
+	info!(
+		"  Network devices:  {}",
+		enabled(config.monitoring.network_devices)
+	);
+	info!(
+		"  Protect sensors:  {}",
+		enabled(config.monitoring.protect_sensors)
+	);
+	info!(
+		"  Protect system:   {}",
+		enabled(config.monitoring.protect_system)
+	);
+	info!(
+		"  Metrics endpoint: {}:{}/metrics (format: OpenMetrics or Prometheus text, via Accept)",
+		config.server.bind_address, config.server.port
+	);
+	info!(
+		"  Bearer auth:      {}",
+		enabled(config.server.bearer_token.is_some())
+	);
+	if config.server.standby {
+		info!("  Standby:          serving 503 until SIGUSR1 or POST /promote");
+	}
+
+	if config.server.bearer_token.is_none() && !is_loopback(&config.server.bind_address) {
+		warn!(
+			"Metrics endpoint is bound to {} without authentication; anyone who can reach it can read your network topology. Set server.bearer_token to secure it.",
+			config.server.bind_address
+		);
+	}
+
+	for name in config.metrics.help_overrides.keys() {
+		if !is_known_family(name, config.metrics.value_scale) {
+			warn!(
+				"metrics.help_overrides contains unknown metric '{}', its override will never be used",
+				name
+			);
+		}
+	}
+}
+
+fn is_loopback(bind_address: &str) -> bool {
+	bind_address == "localhost"
+		|| bind_address
+			.parse::<IpAddr>()
+			.map(|ip| ip.is_loopback())
+			.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::metrics::{isolated_metrics, update_availability, update_poll_metrics};
+
+	async fn health(state: AppState) -> (StatusCode, String) {
+		let response = health_handler(State(state)).await;
+		let status = response.status();
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		(status, String::from_utf8(body.to_vec()).unwrap())
+	}
+
+	#[tokio::test]
+	async fn health_fails_when_controller_is_unreachable() {
+		let _metrics = isolated_metrics().await;
+
+		// Nothing listens on port 1, so discovery fails and leaves the cache empty
+		let mut config = Config::default();
+		config.unifi.ip = "127.0.0.1:1".to_string();
+		config.unifi.request_retries = 0;
+		config.monitoring.protect_sensors = false;
+
+		let network_client = NetworkClient::new(reqwest::Client::new(), &config);
+		let cache = UnifiCache::new();
+		assert!(network_client.initialize_sites(&cache).await.is_err());
+		network_client.poll_statistics(&cache).await.unwrap();
+
+		let (status, body) = health(AppState::new(&config)).await;
+		assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+		assert!(body.contains("network: last poll failed"), "{}", body);
+
+		// A later successful poll makes the exporter healthy again
+		update_poll_metrics("network", true, 0.1);
+		let (status, _) = health(AppState::new(&config)).await;
+		assert_eq!(status, StatusCode::OK);
+
+		// Every enabled subsystem reported as not installed is still unhealthy
+		update_availability("network", false);
+		update_availability("protect", false);
+		let (status, body) = health(AppState::new(&config)).await;
+		assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+		assert!(
+			body.contains("no enabled subsystem is available"),
+			"{}",
+			body
+		);
+	}
 }
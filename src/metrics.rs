@@ -1,17 +1,39 @@
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::http::{HeaderMap, StatusCode, header};
 use axum::response::{IntoResponse, Response};
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
-use std::sync::RwLock;
+use std::sync::atomic::Ordering;
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::AppState;
+use crate::config::{
+	NonFiniteValues, SensorMissingBehavior, ValueScale, default_poll_duration_buckets,
+};
 
 // Metric storage
+//
+// Lock ordering: writers hold at most one of these locks at a time and a render takes
+// its read locks in declaration order, so the poll task and concurrent scrapes never
+// wait on each other in a cycle and can't deadlock.
 pub struct MetricsStore {
+	site_metrics: RwLock<HashMap<String, SiteMetrics>>,
 	device_metrics: RwLock<HashMap<String, DeviceMetrics>>,
 	sensor_metrics: RwLock<HashMap<String, SensorMetrics>>,
+	nvr_metrics: RwLock<HashMap<String, NvrMetrics>>,
 	poll_metrics: RwLock<HashMap<String, PollMetrics>>,
+	exporter_metrics: RwLock<ExporterMetrics>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SiteMetrics {
+	pub site_id: String,
+	pub site_name: String,
+	pub device_count: usize,
 }
 
 #[derive(Clone, Debug)]
@@ -21,12 +43,30 @@ pub struct DeviceMetrics {
 	pub device_id: String,
 	pub device_name: String,
 	pub device_model: String,
-	pub ip_address: String,
+	pub ip_address: Option<String>,
 	pub cpu_usage: Option<f64>,
 	pub memory_usage: Option<f64>,
+	pub temperature: Option<f64>,
 	pub uplink_tx_rate: Option<f64>,
 	pub uplink_rx_rate: Option<f64>,
+	pub poe_budget: Option<f64>,
+	pub poe_consumption: Option<f64>,
+	pub radios: Vec<RadioMetrics>,
+	pub last_heartbeat: Option<DateTime<Utc>>,
 	pub state: i32,
+	pub state_raw: String,
+	pub last_updated: Instant,
+	// Fields holding a last known value because the latest poll reported null
+	pub stale_fields: HashSet<&'static str>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RadioMetrics {
+	// Position in the device's radio list, None when radios are aggregated into a single
+	// per-device value. Keeps series apart when frequencies are missing or shared.
+	pub radio: Option<String>,
+	pub frequency_ghz: Option<String>,
+	pub tx_retries: f64,
 }
 
 #[derive(Clone, Debug)]
@@ -38,427 +78,2204 @@ pub struct SensorMetrics {
 	pub humidity: Option<f64>,
 	pub light: Option<f64>,
 	pub battery: Option<f64>,
+	pub battery_low: Option<bool>,
+	pub battery_voltage: Option<f64>,
 	pub state: i32,
+	pub state_raw: String,
 	pub motion_detected: Option<i32>,
 	pub is_opened: Option<i32>,
+	pub motion_enabled: Option<i32>,
+	pub alarm_enabled: Option<i32>,
+	pub leak_enabled: Option<i32>,
+	pub leak_detected: Option<i32>,
+	pub tampering_detected: Option<i32>,
+	pub alarm_triggered: Option<i32>,
+	pub last_seen: Instant,
+	pub extra_labels: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NvrMetrics {
+	pub nvr_id: String,
+	pub nvr_name: String,
+	pub nvr_model: String,
+	pub cpu_usage: Option<f64>,
+	pub memory_usage: Option<f64>,
+	pub temperature: Option<f64>,
+	pub connected_devices: Option<f64>,
 }
 
 #[derive(Clone, Debug)]
 pub struct PollMetrics {
 	pub success: i32,
 	pub duration: f64,
-	//pub timestamp: Instant,
+	pub duration_histogram: DurationHistogram,
+	pub timestamp: Instant,
+	pub last_success: Option<Instant>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DurationHistogram {
+	pub bounds: Vec<f64>,
+	pub counts: Vec<u64>,
+	pub sum: f64,
+	pub count: u64,
+}
+
+impl DurationHistogram {
+	fn new(bounds: Vec<f64>) -> Self {
+		Self {
+			counts: vec![0; bounds.len()],
+			bounds,
+			sum: 0.0,
+			count: 0,
+		}
+	}
+
+	fn observe(&mut self, value: f64) {
+		if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+			self.counts[i] += 1;
+		}
+		self.sum += value;
+		self.count += 1;
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExporterMetrics {
+	pub render_duration: Option<f64>,
+	pub phase_durations: BTreeMap<&'static str, f64>,
+	pub skipped_ticks: u64,
+	pub poll_task_restarts: u64,
+	pub effective_concurrency: Option<usize>,
+	pub too_old_devices: Option<usize>,
+	pub devices_added: u64,
+	pub devices_removed: u64,
+	pub availability: HashMap<String, bool>,
 }
 
 // Global metrics store
 static METRICS: once_cell::sync::Lazy<MetricsStore> = once_cell::sync::Lazy::new(|| MetricsStore {
+	site_metrics: RwLock::new(HashMap::new()),
 	device_metrics: RwLock::new(HashMap::new()),
 	sensor_metrics: RwLock::new(HashMap::new()),
+	nvr_metrics: RwLock::new(HashMap::new()),
 	poll_metrics: RwLock::new(HashMap::new()),
+	exporter_metrics: RwLock::new(ExporterMetrics::default()),
 });
 
+// Serializes tests that use the global store, handing each one an empty store
+#[cfg(test)]
+pub(crate) async fn isolated_metrics() -> tokio::sync::MutexGuard<'static, ()> {
+	static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+	let guard = LOCK.lock().await;
+	METRICS
+		.site_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.clear();
+	METRICS
+		.device_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.clear();
+	METRICS
+		.sensor_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.clear();
+	METRICS
+		.nvr_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.clear();
+	METRICS
+		.poll_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.clear();
+	*METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner) = ExporterMetrics::default();
+	guard
+}
+
+// Site metrics update functions
+pub fn update_site_metrics(metrics: SiteMetrics) {
+	let mut store = METRICS
+		.site_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.insert(metrics.site_id.clone(), metrics);
+}
+
 // Device metrics update functions
-pub fn update_device_metrics(
-	site_id: &str,
-	site_name: &str,
-	device_id: &str,
-	device_name: &str,
-	device_model: &str,
-	ip_address: &str,
-	cpu_usage: Option<f64>,
-	memory_usage: Option<f64>,
-	uplink_tx_rate: Option<f64>,
-	uplink_rx_rate: Option<f64>,
-	state: i32,
-) {
-	let key = format!("{}_{}", site_id, device_id);
-	let metrics = DeviceMetrics {
-		site_id: site_id.to_string(),
-		site_name: site_name.to_string(),
-		device_id: device_id.to_string(),
-		device_name: device_name.to_string(),
-		device_model: device_model.to_string(),
-		ip_address: ip_address.to_string(),
-		cpu_usage,
-		memory_usage,
-		uplink_tx_rate,
-		uplink_rx_rate,
-		state,
-	};
+pub fn update_device_metrics(mut metrics: DeviceMetrics) {
+	let key = format!("{}_{}", metrics.site_id, metrics.device_id);
+
+	let mut store = METRICS
+		.device_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+
+	// Carry forward last known values of metrics the device temporarily reports as null
+	if let Some(previous) = store.get(&key) {
+		let fields = [
+			("cpu_usage", &mut metrics.cpu_usage, previous.cpu_usage),
+			(
+				"memory_usage",
+				&mut metrics.memory_usage,
+				previous.memory_usage,
+			),
+			(
+				"temperature",
+				&mut metrics.temperature,
+				previous.temperature,
+			),
+			(
+				"uplink_tx_rate",
+				&mut metrics.uplink_tx_rate,
+				previous.uplink_tx_rate,
+			),
+			(
+				"uplink_rx_rate",
+				&mut metrics.uplink_rx_rate,
+				previous.uplink_rx_rate,
+			),
+			("poe_budget", &mut metrics.poe_budget, previous.poe_budget),
+			(
+				"poe_consumption",
+				&mut metrics.poe_consumption,
+				previous.poe_consumption,
+			),
+		];
+		for (field, value, last_known) in fields {
+			if value.is_none() && last_known.is_some() {
+				*value = last_known;
+				metrics.stale_fields.insert(field);
+			}
+		}
+	}
 
-	let mut store = METRICS.device_metrics.write().unwrap();
 	store.insert(key, metrics);
 }
 
+// Drop the metrics of a site and its devices once discovery no longer finds the site
+pub fn remove_site_metrics(site_id: &str) {
+	METRICS
+		.site_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.remove(site_id);
+	METRICS
+		.device_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.retain(|_, device| device.site_id != site_id);
+}
+
+// Drop the metrics of devices that discovery no longer finds in their site
+pub fn remove_device_metrics(site_id: &str, device_ids: &HashSet<String>) {
+	METRICS
+		.device_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.retain(|_, device| device.site_id != site_id || !device_ids.contains(&device.device_id));
+}
+
 // Sensor metrics update functions
-pub fn update_sensor_metrics(
-	sensor_id: &str,
-	sensor_name: &str,
-	mount_type: &str,
-	temperature: Option<f64>,
-	humidity: Option<f64>,
-	light: Option<f64>,
-	battery: Option<f64>,
-	state: i32,
-	motion_detected: Option<i32>,
-	is_opened: Option<i32>,
-) {
-	let metrics = SensorMetrics {
-		sensor_id: sensor_id.to_string(),
-		sensor_name: sensor_name.to_string(),
-		mount_type: mount_type.to_string(),
-		temperature,
-		humidity,
-		light,
-		battery,
-		state,
-		motion_detected,
-		is_opened,
-	};
+pub fn update_sensor_metrics(metrics: SensorMetrics) {
+	let mut store = METRICS
+		.sensor_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.insert(metrics.sensor_id.clone(), metrics);
+}
+
+// Apply the configured behavior to sensors absent from the latest successful poll
+pub fn handle_missing_sensors(seen: &HashSet<&str>, behavior: SensorMissingBehavior) {
+	if behavior == SensorMissingBehavior::Keep {
+		return;
+	}
+
+	let mut store = METRICS
+		.sensor_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+
+	store.retain(|sensor_id, sensor| {
+		if seen.contains(sensor_id.as_str()) {
+			return true;
+		}
 
-	let mut store = METRICS.sensor_metrics.write().unwrap();
-	store.insert(sensor_id.to_string(), metrics);
+		match behavior {
+			SensorMissingBehavior::Keep => true,
+			SensorMissingBehavior::Prune => {
+				info!(
+					"Removing sensor {} ({}) missing from poll, last seen {}s ago",
+					sensor.sensor_name,
+					sensor_id,
+					sensor.last_seen.elapsed().as_secs()
+				);
+				false
+			}
+			SensorMissingBehavior::MarkDisconnected => {
+				if sensor.state != 0 {
+					warn!(
+						"Sensor {} ({}) missing from poll, marking as disconnected",
+						sensor.sensor_name, sensor_id
+					);
+				}
+				sensor.state = 0;
+				// Not an API state, distinguishes sensors the poll no longer returns
+				sensor.state_raw = "MISSING".to_string();
+				true
+			}
+		}
+	});
+}
+
+// Remove devices and sensors that no successful poll has refreshed within the TTL as of now
+pub fn evict_stale_metrics(ttl: Duration, now: Instant) {
+	METRICS
+		.device_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.retain(|_, device| {
+			let age = now.saturating_duration_since(device.last_updated);
+			if age > ttl {
+				info!(
+					"Removing device {} ({}), not updated for {}s",
+					device.device_name,
+					device.device_id,
+					age.as_secs()
+				);
+			}
+			age <= ttl
+		});
+
+	METRICS
+		.sensor_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.retain(|_, sensor| {
+			let age = now.saturating_duration_since(sensor.last_seen);
+			if age > ttl {
+				info!(
+					"Removing sensor {} ({}), not updated for {}s",
+					sensor.sensor_name,
+					sensor.sensor_id,
+					age.as_secs()
+				);
+			}
+			age <= ttl
+		});
+}
+
+// NVR metrics update functions
+pub fn update_nvr_metrics(metrics: NvrMetrics) {
+	let mut store = METRICS
+		.nvr_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.insert(metrics.nvr_id.clone(), metrics);
 }
 
 // Poll metrics update functions
 pub fn update_poll_metrics(poll_type: &str, success: bool, duration: f64) {
-	let metrics = PollMetrics {
-		success: if success { 1 } else { 0 },
-		duration,
-	};
+	let mut store = METRICS
+		.poll_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+
+	let metrics = store
+		.entry(poll_type.to_string())
+		.or_insert_with(|| PollMetrics {
+			success: 0,
+			duration: 0.0,
+			duration_histogram: DurationHistogram::new(poll_duration_buckets().to_vec()),
+			timestamp: Instant::now(),
+			last_success: None,
+		});
+	metrics.success = if success { 1 } else { 0 };
+	metrics.duration = duration;
+	metrics.duration_histogram.observe(duration);
+	metrics.timestamp = Instant::now();
+	if success {
+		metrics.last_success = Some(metrics.timestamp);
+	}
+}
+
+// Outcome of the most recent poll of a type, used by the health check
+pub struct PollStatus {
+	pub success: bool,
+	pub last_success: Option<Instant>,
+}
+
+pub fn poll_status(poll_type: &str) -> Option<PollStatus> {
+	let store = METRICS
+		.poll_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.get(poll_type).map(|poll| PollStatus {
+		success: poll.success == 1,
+		last_success: poll.last_success,
+	})
+}
+
+// Poll duration histogram buckets, configured once at startup
+static POLL_DURATION_BUCKETS: once_cell::sync::OnceCell<Vec<f64>> =
+	once_cell::sync::OnceCell::new();
+
+pub fn set_poll_duration_buckets(buckets: Vec<f64>) {
+	let _ = POLL_DURATION_BUCKETS.set(buckets);
+}
 
-	let mut store = METRICS.poll_metrics.write().unwrap();
-	store.insert(poll_type.to_string(), metrics);
+fn poll_duration_buckets() -> &'static [f64] {
+	POLL_DURATION_BUCKETS.get_or_init(default_poll_duration_buckets)
+}
+
+// Exposition format of the metrics output, negotiated from the Accept header
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+	OpenMetrics,
+	// Classic Prometheus text format: no # UNIT lines and no # EOF marker
+	Prometheus,
+}
+
+impl Format {
+	// Serve OpenMetrics unless the client asks for text/plain without accepting OpenMetrics
+	fn negotiate(headers: &HeaderMap) -> Self {
+		let accept = headers
+			.get(header::ACCEPT)
+			.and_then(|h| h.to_str().ok())
+			.unwrap_or_default();
+
+		if !accept.contains("application/openmetrics-text") && accept.contains("text/plain") {
+			Format::Prometheus
+		} else {
+			Format::OpenMetrics
+		}
+	}
+
+	fn content_type(self) -> &'static str {
+		match self {
+			Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+			Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+		}
+	}
+}
+
+// OpenMetrics text writer
+struct MetricsWriter<'a> {
+	output: String,
+	state: &'a AppState,
+	format: Format,
+	series: usize,
+}
+
+impl<'a> MetricsWriter<'a> {
+	fn new(state: &'a AppState, format: Format) -> Self {
+		Self {
+			output: String::new(),
+			state,
+			format,
+			series: 0,
+		}
+	}
+
+	fn family(&mut self, name: &str, metric_type: &str, unit: Option<&str>, help: &str) {
+		let help_override = self.state.metrics.help_overrides.get(name);
+
+		// The Prometheus text format names counter families after their _total sample
+		let counter_name;
+		let name = if self.format == Format::Prometheus && metric_type == "counter" {
+			counter_name = format!("{}_total", name);
+			&counter_name
+		} else {
+			name
+		};
+
+		match help_override {
+			// User-provided text may contain characters that need escaping
+			Some(help) => {
+				let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+				writeln!(self.output, "# HELP {} {}", name, help).unwrap();
+			}
+			None => writeln!(self.output, "# HELP {} {}", name, help).unwrap(),
+		}
+		writeln!(self.output, "# TYPE {} {}", name, metric_type).unwrap();
+		if let Some(unit) = unit
+			&& self.format == Format::OpenMetrics
+		{
+			writeln!(self.output, "# UNIT {} {}", name, unit).unwrap();
+		}
+	}
+
+	fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+		// A single NaN or infinite reading must not break the whole scrape
+		let value = if value.is_finite() {
+			value
+		} else {
+			let series = labels
+				.iter()
+				.map(|(key, val)| format!(r#"{}="{}""#, key, val))
+				.collect::<Vec<_>>()
+				.join(",");
+
+			match self.state.metrics.non_finite_values {
+				NonFiniteValues::Drop => {
+					warn!(
+						"Dropping non-finite value {} for {}{{{}}}",
+						value, name, series
+					);
+					return;
+				}
+				NonFiniteValues::Clamp => {
+					let clamped = if value.is_nan() {
+						0.0
+					} else {
+						value.clamp(f64::MIN, f64::MAX)
+					};
+					warn!(
+						"Clamping non-finite value {} to {} for {}{{{}}}",
+						value, clamped, name, series
+					);
+					clamped
+				}
+			}
+		};
+
+		self.output.push_str(name);
+
+		// Identify this exporter on every series when configured
+		let instance = self
+			.state
+			.instance_label
+			.as_deref()
+			.map(|instance| ("instance", instance));
+		// Guarantee the grouping label, with a default on series that lack that dimension
+		let group = self
+			.state
+			.group_label
+			.as_deref()
+			.filter(|group| !labels.iter().any(|(key, _)| key == group))
+			.map(|group| (group, self.state.group_label_default.as_str()));
+		let series_labels = labels.len();
+		let labels: Vec<&(&str, &str)> = labels
+			.iter()
+			.chain(group.as_ref())
+			.chain(instance.as_ref())
+			.collect();
+
+		if !labels.is_empty() {
+			self.output.push('{');
+			for (i, (key, val)) in labels.iter().enumerate() {
+				if i > 0 {
+					self.output.push(',');
+				}
+				let mut value = sanitize_label_value(val);
+				// Only names and metadata are truncated, the configured group and instance are kept
+				if i < series_labels && !UNTRUNCATED_LABELS.contains(key) {
+					value = truncate_label_value(value, self.state.metrics.max_label_length);
+				}
+				// Escape last so truncation can't split an escape sequence
+				write!(self.output, r#"{}="{}""#, key, escape_label_value(&value)).unwrap();
+			}
+			self.output.push('}');
+		}
+
+		writeln!(self.output, " {}", value).unwrap();
+		self.series += 1;
+	}
+
+	fn finish(mut self) -> String {
+		// Add EOF marker for OpenMetrics
+		if self.format == Format::OpenMetrics {
+			writeln!(self.output, "# EOF").unwrap();
+		}
+		self.output
+	}
+}
+
+// Families emitted with a fixed name, used to validate metrics.help_overrides
+const METRIC_FAMILIES: &[&str] = &[
+	"unifi_site_device_count",
+	"unifi_device_temperature_celsius",
+	"unifi_device_upload_speed_bits_per_second",
+	"unifi_device_download_speed_bits_per_second",
+	"unifi_switch_poe_budget_watts",
+	"unifi_switch_poe_consumption_watts",
+	"unifi_device_data_age_seconds",
+	"unifi_device_state",
+	"unifi_sensor_temperature_celsius",
+	"unifi_sensor_light_candela_per_square_meter",
+	"unifi_sensor_battery_volts",
+	"unifi_sensor_state",
+	"unifi_sensor_motion_detected",
+	"unifi_sensor_opened",
+	"unifi_sensor_motion_enabled",
+	"unifi_sensor_alarm_enabled",
+	"unifi_sensor_leak_enabled",
+	"unifi_sensor_leak_detected",
+	"unifi_sensor_tampering_detected",
+	"unifi_sensor_alarm_triggered",
+	"unifi_protect_sensors_connected",
+	"unifi_protect_sensors_low_battery",
+	"unifi_protect_nvr_temperature_celsius",
+	"unifi_protect_nvr_connected_devices",
+	"unifi_poll_success",
+	"unifi_poll_duration_seconds",
+	"unifi_poll_cycle_duration_seconds",
+	"unifi_metrics_auth_enabled",
+	"unifi_network_available",
+	"unifi_protect_available",
+	"unifi_poll_skipped_ticks",
+	"unifi_poll_task_restarts",
+	"unifi_discovery_devices_added",
+	"unifi_discovery_devices_removed",
+	"unifi_poll_effective_concurrency",
+	"unifi_poll_devices_too_old",
+	"unifi_exporter_render_duration_seconds",
+	"unifi_exporter_phase_duration_seconds",
+	"unifi_exporter_series_total",
+];
+
+// Families whose name ends with the unit of metrics.value_scale
+const SCALED_METRIC_FAMILIES: &[&str] = &[
+	"unifi_device_cpu_usage",
+	"unifi_device_memory_usage",
+	"unifi_switch_poe_utilization",
+	"unifi_device_radio_tx_retries",
+	"unifi_sensor_humidity",
+	"unifi_sensor_battery",
+	"unifi_protect_nvr_cpu",
+	"unifi_protect_nvr_memory",
+];
+
+pub fn is_known_family(name: &str, scale: ValueScale) -> bool {
+	METRIC_FAMILIES.contains(&name)
+		|| SCALED_METRIC_FAMILIES
+			.iter()
+			.any(|base| scale.name(base) == name)
+}
+
+// Naming and scaling of metrics that the API reports as percentages
+impl ValueScale {
+	fn name(self, base: &str) -> String {
+		format!("{}_{}", base, self.unit())
+	}
+
+	fn unit(self) -> &'static str {
+		match self {
+			ValueScale::Ratio => "ratio",
+			ValueScale::Percent => "percent",
+		}
+	}
+
+	fn description(self) -> &'static str {
+		match self {
+			ValueScale::Ratio => "as a normalized ratio between 0.0 and 1.0.",
+			ValueScale::Percent => "as a percentage between 0 and 100.",
+		}
+	}
+
+	fn apply(self, percentage: f64) -> f64 {
+		match self {
+			ValueScale::Ratio => percentage / 100.0,
+			ValueScale::Percent => percentage,
+		}
+	}
+}
+
+// Strip control characters that would corrupt the exposition format (newlines get escaped instead)
+fn sanitize_label_value(value: &str) -> String {
+	value
+		.chars()
+		.filter(|c| *c == '\n' || !c.is_control())
+		.collect()
+}
+
+// Escape backslashes, double quotes and newlines as required by OpenMetrics
+fn escape_label_value(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'\\' => escaped.push_str("\\\\"),
+			'"' => escaped.push_str("\\\""),
+			'\n' => escaped.push_str("\\n"),
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+// Labels holding IDs or fixed values, which must stay intact to join and query series
+const UNTRUNCATED_LABELS: &[&str] = &[
+	"site_id",
+	"device_id",
+	"sensor_id",
+	"nvr_id",
+	"ip_address",
+	"mount_type",
+	"state_raw",
+	"radio",
+	"frequency_ghz",
+	"stale",
+	"type",
+	"le",
+	"phase",
+];
+
+// Cap label values at max_length characters, marking truncation with an ellipsis
+fn truncate_label_value(value: String, max_length: Option<usize>) -> String {
+	match max_length {
+		Some(max_length) if max_length > 0 && value.chars().count() > max_length => {
+			let mut truncated: String = value.chars().take(max_length - 1).collect();
+			truncated.push('…');
+			truncated
+		}
+		_ => value,
+	}
+}
+
+fn device_labels(device: &DeviceMetrics) -> Vec<(&str, &str)> {
+	let mut labels = vec![
+		("site_id", device.site_id.as_str()),
+		("site_name", device.site_name.as_str()),
+		("device_id", device.device_id.as_str()),
+		("device_name", device.device_name.as_str()),
+		("device_model", device.device_model.as_str()),
+	];
+	if let Some(ref ip_address) = device.ip_address {
+		labels.push(("ip_address", ip_address));
+	}
+	labels
+}
+
+// Labels of a device value, marked stale when any of its fields repeats a last known value.
+// Returns None for stale values unless metrics.emit_null_as_stale is enabled.
+fn device_value_labels<'a>(
+	device: &'a DeviceMetrics,
+	fields: &[&str],
+	emit_stale: bool,
+) -> Option<Vec<(&'a str, &'a str)>> {
+	let mut labels = device_labels(device);
+	if fields.iter().any(|f| device.stale_fields.contains(f)) {
+		if !emit_stale {
+			return None;
+		}
+		labels.push(("stale", "true"));
+	}
+	Some(labels)
+}
+
+fn nvr_labels(nvr: &NvrMetrics) -> [(&str, &str); 3] {
+	[
+		("nvr_id", &nvr.nvr_id),
+		("nvr_name", &nvr.nvr_name),
+		("nvr_model", &nvr.nvr_model),
+	]
+}
+
+fn sensor_labels(sensor: &SensorMetrics) -> Vec<(&str, &str)> {
+	let mut labels = vec![
+		("sensor_id", sensor.sensor_id.as_str()),
+		("sensor_name", sensor.sensor_name.as_str()),
+		("mount_type", sensor.mount_type.as_str()),
+	];
+	labels.extend(
+		sensor
+			.extra_labels
+			.iter()
+			.map(|(key, value)| (key.as_str(), value.as_str())),
+	);
+	labels
+}
+
+// Exporter metrics update functions
+pub fn record_skipped_ticks(count: u64) {
+	let mut store = METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.skipped_ticks += count;
+}
+
+pub fn record_poll_task_restart() {
+	let mut store = METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.poll_task_restarts += 1;
+}
+
+pub fn record_discovery_delta(added: usize, removed: usize) {
+	let mut store = METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.devices_added += added as u64;
+	store.devices_removed += removed as u64;
+}
+
+pub fn update_availability(subsystem: &str, available: bool) {
+	let mut store = METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.availability.insert(subsystem.to_string(), available);
+}
+
+// Subsystems are assumed available until discovery finds them missing
+pub fn is_available(subsystem: &str) -> bool {
+	let store = METRICS
+		.exporter_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.availability.get(subsystem).copied().unwrap_or(true)
+}
+
+pub fn update_effective_concurrency(limit: usize) {
+	let mut store = METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.effective_concurrency = Some(limit);
+}
+
+pub fn update_too_old_devices(count: usize) {
+	let mut store = METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner);
+	store.too_old_devices = Some(count);
 }
 
 // Generate OpenMetrics format output
-fn generate_metrics_output() -> String {
-	let mut output = String::new();
+fn generate_metrics_output(state: &AppState, format: Format) -> String {
+	let mut writer = MetricsWriter::new(state, format);
+	let scale = state.metrics.value_scale;
+
+	// Add site metrics
+	let sites = METRICS
+		.site_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
+
+	if !sites.is_empty() {
+		// Device Count
+		writer.family(
+			"unifi_site_device_count",
+			"gauge",
+			None,
+			"Number of devices discovered in the site",
+		);
+		for site in sites.values() {
+			writer.sample(
+				"unifi_site_device_count",
+				&[("site_id", &site.site_id), ("site_name", &site.site_name)],
+				site.device_count as f64,
+			);
+		}
+	}
 
 	// Add device metrics
-	let devices = METRICS.device_metrics.read().unwrap();
+	let devices = METRICS
+		.device_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
 
 	if !devices.is_empty() {
+		let emit_stale = state.metrics.emit_null_as_stale;
+
 		// CPU Usage
-		writeln!(
-			output,
-			"# HELP unifi_device_cpu_usage_ratio CPU usage of devices as a normalized ratio between 0.0 and 1.0."
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_device_cpu_usage_ratio gauge").unwrap();
-		writeln!(output, "# UNIT unifi_device_cpu_usage_ratio ratio").unwrap();
+		let name = scale.name("unifi_device_cpu_usage");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!("CPU usage of devices {}", scale.description()),
+		);
 		for device in devices.values() {
-			if let Some(cpu) = device.cpu_usage {
-				writeln!(
-					output,
-					r#"unifi_device_cpu_usage_ratio{{site_id="{}",site_name="{}",device_id="{}",device_name="{}",device_model="{}",ip_address="{}"}} {}"#,
-					device.site_id, device.site_name, device.device_id, device.device_name, device.device_model, device.ip_address, cpu / 100.0
-				).unwrap();
+			if let Some(cpu) = device.cpu_usage
+				&& let Some(labels) = device_value_labels(device, &["cpu_usage"], emit_stale)
+			{
+				writer.sample(&name, &labels, scale.apply(cpu));
 			}
 		}
 
 		// Memory Usage
-		writeln!(
-			output,
-			"# HELP unifi_device_memory_usage_ratio Memory usage of devices as a normalized ratio between 0.0 and 1.0."
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_device_memory_usage_ratio gauge").unwrap();
-		writeln!(output, "# UNIT unifi_device_memory_usage_ratio ratio").unwrap();
+		let name = scale.name("unifi_device_memory_usage");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!("Memory usage of devices {}", scale.description()),
+		);
+		for device in devices.values() {
+			if let Some(memory) = device.memory_usage
+				&& let Some(labels) = device_value_labels(device, &["memory_usage"], emit_stale)
+			{
+				writer.sample(&name, &labels, scale.apply(memory));
+			}
+		}
+
+		// Temperature
+		writer.family(
+			"unifi_device_temperature_celsius",
+			"gauge",
+			Some("celsius"),
+			"Internal temperature reported by the device in Celsius",
+		);
 		for device in devices.values() {
-			if let Some(memory) = device.memory_usage {
-				writeln!(
-					output,
-					r#"unifi_device_memory_usage_ratio{{site_id="{}",site_name="{}",device_id="{}",device_name="{}",device_model="{}",ip_address="{}"}} {}"#,
-					device.site_id, device.site_name, device.device_id, device.device_name, device.device_model, device.ip_address, memory / 100.0
-				).unwrap();
+			if let Some(temp) = device.temperature
+				&& let Some(labels) = device_value_labels(device, &["temperature"], emit_stale)
+			{
+				writer.sample("unifi_device_temperature_celsius", &labels, temp);
 			}
 		}
 
 		// Uplink TX Rate
-		writeln!(
-			output,
-			"# HELP unifi_device_upload_speed_bits_per_second Upload speed in bits/sec"
-		)
-		.unwrap();
-		writeln!(
-			output,
-			"# TYPE unifi_device_upload_speed_bits_per_second gauge"
-		)
-		.unwrap();
-		writeln!(
-			output,
-			"# UNIT unifi_device_upload_speed_bits_per_second bits_per_second"
-		)
-		.unwrap();
+		writer.family(
+			"unifi_device_upload_speed_bits_per_second",
+			"gauge",
+			Some("bits_per_second"),
+			"Upload speed in bits/sec",
+		);
 		for device in devices.values() {
-			if let Some(tx_rate) = device.uplink_tx_rate {
-				writeln!(
-					output,
-					r#"unifi_device_upload_speed_bits_per_second{{site_id="{}",site_name="{}",device_id="{}",device_name="{}",device_model="{}",ip_address="{}"}} {}"#,
-					device.site_id, device.site_name, device.device_id, device.device_name, device.device_model, device.ip_address, tx_rate
-				).unwrap();
+			if let Some(tx_rate) = device.uplink_tx_rate
+				&& let Some(labels) = device_value_labels(device, &["uplink_tx_rate"], emit_stale)
+			{
+				writer.sample(
+					"unifi_device_upload_speed_bits_per_second",
+					&labels,
+					tx_rate,
+				);
 			}
 		}
 
 		// Uplink RX Rate
-		writeln!(
-			output,
-			"# HELP unifi_device_download_speed_bits_per_second Download speed in bits/sec"
-		)
-		.unwrap();
-		writeln!(
-			output,
-			"# TYPE unifi_device_download_speed_bits_per_second gauge"
-		)
-		.unwrap();
-		writeln!(
-			output,
-			"# UNIT unifi_device_download_speed_bits_per_second bits_per_second"
-		)
-		.unwrap();
+		writer.family(
+			"unifi_device_download_speed_bits_per_second",
+			"gauge",
+			Some("bits_per_second"),
+			"Download speed in bits/sec",
+		);
+		for device in devices.values() {
+			if let Some(rx_rate) = device.uplink_rx_rate
+				&& let Some(labels) = device_value_labels(device, &["uplink_rx_rate"], emit_stale)
+			{
+				writer.sample(
+					"unifi_device_download_speed_bits_per_second",
+					&labels,
+					rx_rate,
+				);
+			}
+		}
+
+		// PoE Budget
+		writer.family(
+			"unifi_switch_poe_budget_watts",
+			"gauge",
+			Some("watts"),
+			"Total PoE power budget of the switch in watts",
+		);
+		for device in devices.values() {
+			if let Some(budget) = device.poe_budget
+				&& let Some(labels) = device_value_labels(device, &["poe_budget"], emit_stale)
+			{
+				writer.sample("unifi_switch_poe_budget_watts", &labels, budget);
+			}
+		}
+
+		// PoE Consumption
+		writer.family(
+			"unifi_switch_poe_consumption_watts",
+			"gauge",
+			Some("watts"),
+			"Current PoE power consumption of the switch in watts",
+		);
+		for device in devices.values() {
+			if let Some(consumption) = device.poe_consumption
+				&& let Some(labels) = device_value_labels(device, &["poe_consumption"], emit_stale)
+			{
+				writer.sample("unifi_switch_poe_consumption_watts", &labels, consumption);
+			}
+		}
+
+		// PoE Utilization
+		let name = scale.name("unifi_switch_poe_utilization");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!(
+				"PoE power consumption relative to the switch budget {}",
+				scale.description()
+			),
+		);
+		for device in devices.values() {
+			if let (Some(budget), Some(consumption)) = (device.poe_budget, device.poe_consumption)
+				&& budget > 0.0
+				&& let Some(labels) =
+					device_value_labels(device, &["poe_budget", "poe_consumption"], emit_stale)
+			{
+				writer.sample(&name, &labels, scale.apply(consumption / budget * 100.0));
+			}
+		}
+
+		// Radio TX Retries
+		let name = scale.name("unifi_device_radio_tx_retries");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!(
+				"Share of transmitted WiFi packets that had to be retried {}",
+				scale.description()
+			),
+		);
 		for device in devices.values() {
-			if let Some(rx_rate) = device.uplink_rx_rate {
-				writeln!(
-					output,
-					r#"unifi_device_download_speed_bits_per_second{{site_id="{}",site_name="{}",device_id="{}",device_name="{}",device_model="{}",ip_address="{}"}} {}"#,
-					device.site_id, device.site_name, device.device_id, device.device_name, device.device_model, device.ip_address, rx_rate
-				).unwrap();
+			for radio in &device.radios {
+				let mut labels = device_labels(device);
+				if let Some(ref index) = radio.radio {
+					labels.push(("radio", index));
+				}
+				if let Some(ref frequency) = radio.frequency_ghz {
+					labels.push(("frequency_ghz", frequency));
+				}
+				writer.sample(&name, &labels, scale.apply(radio.tx_retries));
+			}
+		}
+
+		// Data Age
+		writer.family(
+			"unifi_device_data_age_seconds",
+			"gauge",
+			Some("seconds"),
+			"Time since the device last reported statistics to the controller in seconds",
+		);
+		let now = Utc::now();
+		for device in devices.values() {
+			if let Some(heartbeat) = device.last_heartbeat {
+				writer.sample(
+					"unifi_device_data_age_seconds",
+					&device_labels(device),
+					(now - heartbeat).num_milliseconds() as f64 / 1000.0,
+				);
 			}
 		}
 
 		// Device State
-		writeln!(
-			output,
-			"# HELP unifi_device_state Device state (1 = online, 0 = offline)"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_device_state gauge").unwrap();
+		writer.family(
+			"unifi_device_state",
+			"gauge",
+			None,
+			"Device state (1 = online, 0 = offline), with the state reported by the API in state_raw",
+		);
 		for device in devices.values() {
-			writeln!(
-				output,
-				r#"unifi_device_state{{site_id="{}",site_name="{}",device_id="{}",device_name="{}",device_model="{}",ip_address="{}"}} {}"#,
-				device.site_id, device.site_name, device.device_id, device.device_name, device.device_model, device.ip_address, device.state
-			).unwrap();
+			let mut labels = device_labels(device);
+			labels.push(("state_raw", &device.state_raw));
+			writer.sample("unifi_device_state", &labels, device.state as f64);
 		}
 	}
 
 	// Add sensor metrics
-	let sensors = METRICS.sensor_metrics.read().unwrap();
+	let sensors = METRICS
+		.sensor_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
 
 	if !sensors.is_empty() {
 		// Temperature
-		writeln!(
-			output,
-			"# HELP unifi_sensor_temperature_celsius Temperature reading from sensor in Celsius"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_sensor_temperature_celsius gauge").unwrap();
-		writeln!(output, "# UNIT unifi_sensor_temperature_celsius celsius").unwrap();
+		writer.family(
+			"unifi_sensor_temperature_celsius",
+			"gauge",
+			Some("celsius"),
+			"Temperature reading from sensor in Celsius",
+		);
 		for sensor in sensors.values() {
 			if let Some(temp) = sensor.temperature {
-				writeln!(
-					output,
-					r#"unifi_sensor_temperature_celsius{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-					sensor.sensor_id, sensor.sensor_name, sensor.mount_type, temp
-				)
-				.unwrap();
+				writer.sample(
+					"unifi_sensor_temperature_celsius",
+					&sensor_labels(sensor),
+					temp,
+				);
 			}
 		}
 
 		// Humidity
-		writeln!(
-			output,
-			"# HELP unifi_sensor_humidity_ratio Current relative humidity measured by the sensor as a normalized ratio between 0.0 and 1.0."
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_sensor_humidity_ratio gauge").unwrap();
-		writeln!(output, "# UNIT unifi_sensor_humidity_ratio ratio").unwrap();
+		let name = scale.name("unifi_sensor_humidity");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!(
+				"Current relative humidity measured by the sensor {}",
+				scale.description()
+			),
+		);
 		for sensor in sensors.values() {
 			if let Some(humidity) = sensor.humidity {
-				writeln!(
-					output,
-					r#"unifi_sensor_humidity_ratio{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-					sensor.sensor_id,
-					sensor.sensor_name,
-					sensor.mount_type,
-					humidity / 100.0
-				)
-				.unwrap();
+				writer.sample(&name, &sensor_labels(sensor), scale.apply(humidity));
 			}
 		}
 
 		// Light
-		writeln!(
-			output,
-			"# HELP unifi_sensor_light_candela_per_square_meter Current light level measured by the sensor in candela per square meter."
-		)
-		.unwrap();
-		writeln!(
-			output,
-			"# TYPE unifi_sensor_light_candela_per_square_meter gauge"
-		)
-		.unwrap();
-		writeln!(
-			output,
-			"# UNIT unifi_sensor_light_candela_per_square_meter candela_per_square_meter"
-		)
-		.unwrap();
+		writer.family(
+			"unifi_sensor_light_candela_per_square_meter",
+			"gauge",
+			Some("candela_per_square_meter"),
+			"Current light level measured by the sensor in candela per square meter.",
+		);
 		for sensor in sensors.values() {
 			if let Some(light) = sensor.light {
-				writeln!(
-					output,
-					r#"unifi_sensor_light_candela_per_square_meter{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-					sensor.sensor_id, sensor.sensor_name, sensor.mount_type, light
-				)
-				.unwrap();
+				writer.sample(
+					"unifi_sensor_light_candela_per_square_meter",
+					&sensor_labels(sensor),
+					light,
+				);
 			}
 		}
 
 		// Battery
-		writeln!(
-			output,
-			"# HELP unifi_sensor_battery_ratio Battery level of the sensor as a normalized ratio between 0.0 and 1.0."
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_sensor_battery_ratio gauge").unwrap();
-		writeln!(output, "# UNIT unifi_sensor_battery_ratio ratio").unwrap();
+		let name = scale.name("unifi_sensor_battery");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!("Battery level of the sensor {}", scale.description()),
+		);
 		for sensor in sensors.values() {
 			if let Some(battery) = sensor.battery {
-				writeln!(
-					output,
-					r#"unifi_sensor_battery_ratio{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-					sensor.sensor_id,
-					sensor.sensor_name,
-					sensor.mount_type,
-					battery / 100.0
-				)
-				.unwrap();
+				writer.sample(&name, &sensor_labels(sensor), scale.apply(battery));
+			}
+		}
+
+		// Battery Voltage
+		writer.family(
+			"unifi_sensor_battery_volts",
+			"gauge",
+			Some("volts"),
+			"Battery voltage of the sensor in volts",
+		);
+		for sensor in sensors.values() {
+			if let Some(voltage) = sensor.battery_voltage {
+				writer.sample(
+					"unifi_sensor_battery_volts",
+					&sensor_labels(sensor),
+					voltage,
+				);
 			}
 		}
 
 		// Sensor State
-		writeln!(
-			output,
-			"# HELP unifi_sensor_state Sensor connection state (1 = connected, 0 = disconnected)"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_sensor_state gauge").unwrap();
+		writer.family(
+			"unifi_sensor_state",
+			"gauge",
+			None,
+			"Sensor connection state (1 = connected, 0 = disconnected), with the state reported by the API in state_raw",
+		);
 		for sensor in sensors.values() {
-			writeln!(
-				output,
-				r#"unifi_sensor_state{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-				sensor.sensor_id, sensor.sensor_name, sensor.mount_type, sensor.state
-			)
-			.unwrap();
+			let mut labels = sensor_labels(sensor);
+			labels.push(("state_raw", &sensor.state_raw));
+			writer.sample("unifi_sensor_state", &labels, sensor.state as f64);
 		}
 
 		// Motion Detection
-		writeln!(
-			output,
-			"# HELP unifi_sensor_motion_detected Motion detection status (1 = detected, 0 = not detected)"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_sensor_motion_detected gauge").unwrap();
+		writer.family(
+			"unifi_sensor_motion_detected",
+			"gauge",
+			None,
+			"Motion detection status (1 = detected, 0 = not detected)",
+		);
 		for sensor in sensors.values() {
 			if let Some(motion) = sensor.motion_detected {
-				writeln!(
-					output,
-					r#"unifi_sensor_motion_detected{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-					sensor.sensor_id, sensor.sensor_name, sensor.mount_type, motion
-				)
-				.unwrap();
+				writer.sample(
+					"unifi_sensor_motion_detected",
+					&sensor_labels(sensor),
+					motion as f64,
+				);
 			}
 		}
 
 		// Door/Window Sensor
-		writeln!(
-			output,
-			"# HELP unifi_sensor_opened Door/window sensor status (1 = opened, 0 = closed)"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_sensor_opened gauge").unwrap();
+		writer.family(
+			"unifi_sensor_opened",
+			"gauge",
+			None,
+			"Door/window sensor status (1 = opened, 0 = closed)",
+		);
 		for sensor in sensors.values() {
 			if let Some(opened) = sensor.is_opened {
-				writeln!(
-					output,
-					r#"unifi_sensor_opened{{sensor_id="{}",sensor_name="{}",mount_type="{}"}} {}"#,
-					sensor.sensor_id, sensor.sensor_name, sensor.mount_type, opened
-				)
-				.unwrap();
+				writer.sample("unifi_sensor_opened", &sensor_labels(sensor), opened as f64);
+			}
+		}
+
+		// Motion Detection Enabled
+		writer.family(
+			"unifi_sensor_motion_enabled",
+			"gauge",
+			None,
+			"Whether motion detection is enabled on the sensor (1 = enabled, 0 = disabled)",
+		);
+		for sensor in sensors.values() {
+			if let Some(enabled) = sensor.motion_enabled {
+				writer.sample(
+					"unifi_sensor_motion_enabled",
+					&sensor_labels(sensor),
+					enabled as f64,
+				);
+			}
+		}
+
+		// Alarm Detection Enabled
+		writer.family(
+			"unifi_sensor_alarm_enabled",
+			"gauge",
+			None,
+			"Whether alarm sound detection is enabled on the sensor (1 = enabled, 0 = disabled)",
+		);
+		for sensor in sensors.values() {
+			if let Some(enabled) = sensor.alarm_enabled {
+				writer.sample(
+					"unifi_sensor_alarm_enabled",
+					&sensor_labels(sensor),
+					enabled as f64,
+				);
+			}
+		}
+
+		// Leak Detection Enabled
+		writer.family(
+			"unifi_sensor_leak_enabled",
+			"gauge",
+			None,
+			"Whether internal or external leak detection is enabled on the sensor (1 = enabled, 0 = disabled)",
+		);
+		for sensor in sensors.values() {
+			if let Some(enabled) = sensor.leak_enabled {
+				writer.sample(
+					"unifi_sensor_leak_enabled",
+					&sensor_labels(sensor),
+					enabled as f64,
+				);
+			}
+		}
+
+		// Leak Detected
+		writer.family(
+			"unifi_sensor_leak_detected",
+			"gauge",
+			None,
+			"Whether the sensor detected a leak in the last 5 minutes (1 = detected, 0 = not detected)",
+		);
+		for sensor in sensors.values() {
+			if let Some(detected) = sensor.leak_detected {
+				writer.sample(
+					"unifi_sensor_leak_detected",
+					&sensor_labels(sensor),
+					detected as f64,
+				);
+			}
+		}
+
+		// Tampering Detected
+		writer.family(
+			"unifi_sensor_tampering_detected",
+			"gauge",
+			None,
+			"Whether the sensor detected tampering in the last 5 minutes (1 = detected, 0 = not detected)",
+		);
+		for sensor in sensors.values() {
+			if let Some(detected) = sensor.tampering_detected {
+				writer.sample(
+					"unifi_sensor_tampering_detected",
+					&sensor_labels(sensor),
+					detected as f64,
+				);
+			}
+		}
+
+		// Alarm Triggered
+		writer.family(
+			"unifi_sensor_alarm_triggered",
+			"gauge",
+			None,
+			"Whether the sensor's alarm was triggered in the last 5 minutes (1 = triggered, 0 = not triggered)",
+		);
+		for sensor in sensors.values() {
+			if let Some(triggered) = sensor.alarm_triggered {
+				writer.sample(
+					"unifi_sensor_alarm_triggered",
+					&sensor_labels(sensor),
+					triggered as f64,
+				);
+			}
+		}
+
+		// Connected Sensors
+		writer.family(
+			"unifi_protect_sensors_connected",
+			"gauge",
+			None,
+			"Number of Protect sensors currently connected",
+		);
+		writer.sample(
+			"unifi_protect_sensors_connected",
+			&[],
+			sensors.values().filter(|s| s.state == 1).count() as f64,
+		);
+
+		// Low Battery Sensors
+		writer.family(
+			"unifi_protect_sensors_low_battery",
+			"gauge",
+			None,
+			"Number of Protect sensors reporting a low battery",
+		);
+		writer.sample(
+			"unifi_protect_sensors_low_battery",
+			&[],
+			sensors
+				.values()
+				.filter(|s| s.battery_low == Some(true))
+				.count() as f64,
+		);
+	}
+
+	// Add NVR metrics
+	let nvrs = METRICS
+		.nvr_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
+
+	if !nvrs.is_empty() {
+		// CPU Usage
+		let name = scale.name("unifi_protect_nvr_cpu");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!("CPU usage of the Protect NVR {}", scale.description()),
+		);
+		for nvr in nvrs.values() {
+			if let Some(cpu) = nvr.cpu_usage {
+				writer.sample(&name, &nvr_labels(nvr), scale.apply(cpu));
+			}
+		}
+
+		// Memory Usage
+		let name = scale.name("unifi_protect_nvr_memory");
+		writer.family(
+			&name,
+			"gauge",
+			Some(scale.unit()),
+			&format!("Memory usage of the Protect NVR {}", scale.description()),
+		);
+		for nvr in nvrs.values() {
+			if let Some(memory) = nvr.memory_usage {
+				writer.sample(&name, &nvr_labels(nvr), scale.apply(memory));
+			}
+		}
+
+		// Temperature
+		writer.family(
+			"unifi_protect_nvr_temperature_celsius",
+			"gauge",
+			Some("celsius"),
+			"CPU temperature of the Protect NVR in Celsius",
+		);
+		for nvr in nvrs.values() {
+			if let Some(temp) = nvr.temperature {
+				writer.sample(
+					"unifi_protect_nvr_temperature_celsius",
+					&nvr_labels(nvr),
+					temp,
+				);
+			}
+		}
+
+		// Connected Devices
+		writer.family(
+			"unifi_protect_nvr_connected_devices",
+			"gauge",
+			None,
+			"Number of cameras currently connected to the Protect NVR",
+		);
+		for nvr in nvrs.values() {
+			if let Some(count) = nvr.connected_devices {
+				writer.sample(
+					"unifi_protect_nvr_connected_devices",
+					&nvr_labels(nvr),
+					count,
+				);
 			}
 		}
 	}
 
 	// Add poll metrics
-	let polls = METRICS.poll_metrics.read().unwrap();
+	let polls = METRICS
+		.poll_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
 
 	if !polls.is_empty() {
 		// Poll Success
-		writeln!(
-			output,
-			"# HELP unifi_poll_success Whether the last poll was successful (1 = success, 0 = failure)"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_poll_success gauge").unwrap();
+		writer.family(
+			"unifi_poll_success",
+			"gauge",
+			None,
+			"Whether the last poll was successful (1 = success, 0 = failure)",
+		);
 		for (poll_type, metrics) in polls.iter() {
-			writeln!(
-				output,
-				r#"unifi_poll_success{{type="{}"}} {}"#,
-				poll_type, metrics.success
-			)
-			.unwrap();
+			writer.sample(
+				"unifi_poll_success",
+				&[("type", poll_type)],
+				metrics.success as f64,
+			);
 		}
 
 		// Poll Duration
-		writeln!(
-			output,
-			"# HELP unifi_poll_duration_seconds Duration of the last poll in seconds"
-		)
-		.unwrap();
-		writeln!(output, "# TYPE unifi_poll_duration_seconds gauge").unwrap();
-		writeln!(output, "# UNIT unifi_poll_duration_seconds seconds").unwrap();
+		writer.family(
+			"unifi_poll_duration_seconds",
+			"gauge",
+			Some("seconds"),
+			"Duration of the last poll in seconds",
+		);
 		for (poll_type, metrics) in polls.iter() {
-			writeln!(
-				output,
-				r#"unifi_poll_duration_seconds{{type="{}"}} {}"#,
-				poll_type, metrics.duration
-			)
-			.unwrap();
+			writer.sample(
+				"unifi_poll_duration_seconds",
+				&[("type", poll_type)],
+				metrics.duration,
+			);
+		}
+
+		// Poll Duration Histogram
+		writer.family(
+			"unifi_poll_cycle_duration_seconds",
+			"histogram",
+			Some("seconds"),
+			"Distribution of poll durations in seconds",
+		);
+		for (poll_type, metrics) in polls.iter() {
+			let histogram = &metrics.duration_histogram;
+			let mut cumulative = 0;
+			for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+				cumulative += count;
+				writer.sample(
+					"unifi_poll_cycle_duration_seconds_bucket",
+					&[("type", poll_type), ("le", &bound.to_string())],
+					cumulative as f64,
+				);
+			}
+			writer.sample(
+				"unifi_poll_cycle_duration_seconds_bucket",
+				&[("type", poll_type), ("le", "+Inf")],
+				histogram.count as f64,
+			);
+			writer.sample(
+				"unifi_poll_cycle_duration_seconds_sum",
+				&[("type", poll_type)],
+				histogram.sum,
+			);
+			writer.sample(
+				"unifi_poll_cycle_duration_seconds_count",
+				&[("type", poll_type)],
+				histogram.count as f64,
+			);
+		}
+	}
+
+	// Add exporter metrics
+	let exporter = METRICS
+		.exporter_metrics
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
+
+	// Authentication
+	writer.family(
+		"unifi_metrics_auth_enabled",
+		"gauge",
+		None,
+		"Whether bearer token authentication is enabled for the metrics endpoint (1 = enabled, 0 = disabled)",
+	);
+	writer.sample(
+		"unifi_metrics_auth_enabled",
+		&[],
+		if state.bearer_token.is_some() {
+			1.0
+		} else {
+			0.0
+		},
+	);
+
+	// Subsystem Availability
+	for (subsystem, available) in exporter.availability.iter() {
+		let name = format!("unifi_{}_available", subsystem);
+		let application = match subsystem.as_str() {
+			"network" => "Network",
+			"protect" => "Protect",
+			other => other,
+		};
+		writer.family(
+			&name,
+			"gauge",
+			None,
+			&format!(
+				"Whether UniFi {} is installed on the console (1 = available, 0 = unavailable)",
+				application
+			),
+		);
+		writer.sample(&name, &[], if *available { 1.0 } else { 0.0 });
+	}
+
+	// Skipped Poll Ticks
+	writer.family(
+		"unifi_poll_skipped_ticks",
+		"counter",
+		None,
+		"Number of poll ticks skipped because a poll cycle overran the poll interval",
+	);
+	writer.sample(
+		"unifi_poll_skipped_ticks_total",
+		&[],
+		exporter.skipped_ticks as f64,
+	);
+
+	// Poll Task Restarts
+	writer.family(
+		"unifi_poll_task_restarts",
+		"counter",
+		None,
+		"Number of times the polling task was restarted after a panic",
+	);
+	writer.sample(
+		"unifi_poll_task_restarts_total",
+		&[],
+		exporter.poll_task_restarts as f64,
+	);
+
+	// Discovery Deltas
+	writer.family(
+		"unifi_discovery_devices_added",
+		"counter",
+		None,
+		"Number of devices that appeared in previously discovered sites during rediscovery",
+	);
+	writer.sample(
+		"unifi_discovery_devices_added_total",
+		&[],
+		exporter.devices_added as f64,
+	);
+
+	writer.family(
+		"unifi_discovery_devices_removed",
+		"counter",
+		None,
+		"Number of devices that disappeared from previously discovered sites during rediscovery",
+	);
+	writer.sample(
+		"unifi_discovery_devices_removed_total",
+		&[],
+		exporter.devices_removed as f64,
+	);
+
+	// Effective Poll Concurrency
+	if let Some(limit) = exporter.effective_concurrency {
+		writer.family(
+			"unifi_poll_effective_concurrency",
+			"gauge",
+			None,
+			"Current limit on concurrent device statistics requests after adaptive backoff",
+		);
+		writer.sample("unifi_poll_effective_concurrency", &[], limit as f64);
+	}
+
+	// Devices With Too Old Statistics
+	if let Some(count) = exporter.too_old_devices {
+		writer.family(
+			"unifi_poll_devices_too_old",
+			"gauge",
+			None,
+			"Number of devices skipped in the last poll because their statistics were older than max_data_age_secs",
+		);
+		writer.sample("unifi_poll_devices_too_old", &[], count as f64);
+	}
+
+	// Render Duration (reported for the previous scrape)
+	if let Some(duration) = exporter.render_duration {
+		writer.family(
+			"unifi_exporter_render_duration_seconds",
+			"gauge",
+			Some("seconds"),
+			"Time spent rendering the previous metrics output in seconds",
+		);
+		writer.sample("unifi_exporter_render_duration_seconds", &[], duration);
+	}
+
+	// Scrape Phase Durations (auth_check, render and filter, as of their last run)
+	if !exporter.phase_durations.is_empty() {
+		writer.family(
+			"unifi_exporter_phase_duration_seconds",
+			"gauge",
+			Some("seconds"),
+			"Time spent in each metrics scrape phase the last time it ran, in seconds",
+		);
+		for (phase, duration) in &exporter.phase_durations {
+			writer.sample(
+				"unifi_exporter_phase_duration_seconds",
+				&[("phase", phase)],
+				*duration,
+			);
 		}
 	}
 
-	// Add EOF marker for OpenMetrics
-	writeln!(output, "# EOF").unwrap();
+	// Series Count (including this one)
+	writer.family(
+		"unifi_exporter_series_total",
+		"gauge",
+		None,
+		"Number of series produced by this metrics render",
+	);
+	let series = writer.series + 1;
+	writer.sample("unifi_exporter_series_total", &[], series as f64);
 
-	output
+	writer.finish()
 }
 
-pub async fn metrics_handler(headers: HeaderMap, State(state): State<AppState>) -> Response {
-	if let Some(ref required_token) = state.bearer_token {
-		let auth_header = headers.get("authorization").and_then(|h| h.to_str().ok());
+// Record how long a phase of the current scrape took, exported on the next scrape
+fn record_phase_duration(phase: &'static str, start: Instant) {
+	METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.phase_durations
+		.insert(phase, start.elapsed().as_secs_f64());
+}
 
-		match auth_header {
-			Some(header) if header == format!("Bearer {}", required_token) => {
-				// Authorized
-			}
-			_ => {
-				return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-			}
+// Keep only the metric families whose name starts with one of the given prefixes
+fn filter_families(output: &str, prefixes: &[&str]) -> String {
+	let mut filtered = String::new();
+
+	for line in output.lines() {
+		let name = ["# HELP ", "# TYPE ", "# UNIT "]
+			.iter()
+			.find_map(|marker| line.strip_prefix(marker))
+			.unwrap_or(line);
+
+		if line == "# EOF" || prefixes.iter().any(|p| name.starts_with(p)) {
+			filtered.push_str(line);
+			filtered.push('\n');
+		}
+	}
+
+	filtered
+}
+
+#[derive(Deserialize)]
+pub struct MetricsQuery {
+	prefix: Option<String>,
+}
+
+// Families rendered on the per-subsystem /metrics/network and /metrics/protect routes
+const NETWORK_FAMILY_PREFIXES: &[&str] = &[
+	"unifi_site_",
+	"unifi_device_",
+	"unifi_switch_",
+	"unifi_network_",
+];
+const PROTECT_FAMILY_PREFIXES: &[&str] = &["unifi_sensor_", "unifi_protect_"];
+
+pub async fn metrics_handler(
+	headers: HeaderMap,
+	Query(query): Query<MetricsQuery>,
+	State(state): State<AppState>,
+) -> Response {
+	let token = state.bearer_token.as_deref();
+	render_metrics(&headers, &query, &state, token, &[])
+}
+
+// Subsystem routes use their own token when configured, otherwise the global one
+pub async fn network_metrics_handler(
+	headers: HeaderMap,
+	Query(query): Query<MetricsQuery>,
+	State(state): State<AppState>,
+) -> Response {
+	let token = state
+		.network_bearer_token
+		.as_deref()
+		.or(state.bearer_token.as_deref());
+	render_metrics(&headers, &query, &state, token, NETWORK_FAMILY_PREFIXES)
+}
+
+pub async fn protect_metrics_handler(
+	headers: HeaderMap,
+	Query(query): Query<MetricsQuery>,
+	State(state): State<AppState>,
+) -> Response {
+	let token = state
+		.protect_bearer_token
+		.as_deref()
+		.or(state.bearer_token.as_deref());
+	render_metrics(&headers, &query, &state, token, PROTECT_FAMILY_PREFIXES)
+}
+
+pub fn is_authorized(headers: &HeaderMap, required_token: Option<&str>) -> bool {
+	match required_token {
+		Some(required_token) => {
+			let auth_header = headers.get("authorization").and_then(|h| h.to_str().ok());
+			auth_header == Some(format!("Bearer {}", required_token).as_str())
 		}
+		None => true,
+	}
+}
+
+fn render_metrics(
+	headers: &HeaderMap,
+	query: &MetricsQuery,
+	state: &AppState,
+	required_token: Option<&str>,
+	subsystem_prefixes: &[&str],
+) -> Response {
+	let auth_start = Instant::now();
+	let authorized = is_authorized(headers, required_token);
+	record_phase_duration("auth_check", auth_start);
+
+	if !authorized {
+		return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
 	}
 
-	let metrics_output = generate_metrics_output();
+	// A warm standby keeps polling but doesn't serve until promoted
+	if state.standby.load(Ordering::Relaxed) {
+		return (StatusCode::SERVICE_UNAVAILABLE, "Standby").into_response();
+	}
+
+	let render_start = Instant::now();
+	let format = Format::negotiate(headers);
+	let metrics_output = generate_metrics_output(state, format);
+	METRICS
+		.exporter_metrics
+		.write()
+		.unwrap_or_else(PoisonError::into_inner)
+		.render_duration = Some(render_start.elapsed().as_secs_f64());
+	record_phase_duration("render", render_start);
+
+	// Optional comma-separated family name prefixes, e.g. ?prefix=unifi_sensor,unifi_poll
+	let prefixes: Vec<&str> = query
+		.prefix
+		.as_deref()
+		.map(|p| {
+			p.split(',')
+				.map(str::trim)
+				.filter(|p| !p.is_empty())
+				.collect()
+		})
+		.unwrap_or_default();
+
+	let metrics_output = if prefixes.is_empty() && subsystem_prefixes.is_empty() {
+		metrics_output
+	} else {
+		let filter_start = Instant::now();
+		let mut filtered = metrics_output;
+		for prefixes in [subsystem_prefixes, &prefixes] {
+			if !prefixes.is_empty() {
+				filtered = filter_families(&filtered, prefixes);
+			}
+		}
+		record_phase_duration("filter", filter_start);
+		filtered
+	};
 
 	Response::builder()
 		.status(StatusCode::OK)
-		.header(
-			header::CONTENT_TYPE,
-			"application/openmetrics-text; version=1.0.0; charset=utf-8",
-		)
+		.header(header::CONTENT_TYPE, format.content_type())
 		.body(metrics_output.into())
 		.unwrap()
 }
 
-// Re-export once_cell for use in the module
-pub(crate) use once_cell;
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+	use std::time::Duration;
+
+	#[derive(Debug)]
+	struct Sample {
+		name: String,
+		labels: Vec<(String, String)>,
+		value: f64,
+	}
+
+	impl Sample {
+		fn label(&self, key: &str) -> Option<&str> {
+			self
+				.labels
+				.iter()
+				.find(|(k, _)| k == key)
+				.map(|(_, v)| v.as_str())
+		}
+	}
+
+	// Strict parser for the text format the writer emits, panicking on anything invalid
+	fn parse(output: &str) -> Vec<Sample> {
+		let mut lines = output
+			.strip_suffix('\n')
+			.expect("output must end with a newline")
+			.split('\n')
+			.peekable();
+		let mut samples = Vec::new();
+
+		while let Some(line) = lines.next() {
+			if let Some(comment) = line.strip_prefix("# ") {
+				if comment == "EOF" {
+					assert!(lines.peek().is_none(), "content after # EOF");
+					continue;
+				}
+				let (kind, rest) = comment
+					.split_once(' ')
+					.unwrap_or_else(|| panic!("invalid comment line {:?}", line));
+				assert!(
+					matches!(kind, "HELP" | "TYPE" | "UNIT") && !rest.is_empty(),
+					"invalid comment line {:?}",
+					line
+				);
+				continue;
+			}
+			samples.push(parse_sample(line));
+		}
+
+		samples
+	}
+
+	fn parse_sample(line: &str) -> Sample {
+		let mut chars = line.chars().peekable();
+
+		let mut name = String::new();
+		while let Some(&c) = chars.peek() {
+			if !(c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+				break;
+			}
+			name.push(c);
+			chars.next();
+		}
+		assert!(!name.is_empty(), "missing metric name in {:?}", line);
+
+		let mut labels = Vec::new();
+		if chars.peek() == Some(&'{') {
+			chars.next();
+			loop {
+				let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+				assert!(
+					!key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+					"invalid label name {:?} in {:?}",
+					key,
+					line
+				);
+				assert_eq!(
+					chars.next(),
+					Some('"'),
+					"unquoted label value in {:?}",
+					line
+				);
+
+				let mut value = String::new();
+				loop {
+					match chars.next() {
+						Some('\\') => match chars.next() {
+							Some('\\') => value.push('\\'),
+							Some('"') => value.push('"'),
+							Some('n') => value.push('\n'),
+							other => panic!("invalid escape {:?} in {:?}", other, line),
+						},
+						Some('"') => break,
+						Some(c) => {
+							assert!(!c.is_control(), "raw control character in {:?}", line);
+							value.push(c);
+						}
+						None => panic!("unterminated label value in {:?}", line),
+					}
+				}
+				labels.push((key, value));
+
+				match chars.next() {
+					Some(',') => continue,
+					Some('}') => break,
+					other => panic!("unexpected {:?} after label in {:?}", other, line),
+				}
+			}
+		}
+
+		assert_eq!(chars.next(), Some(' '), "missing value in {:?}", line);
+		let value: String = chars.collect();
+		let value = value
+			.parse::<f64>()
+			.unwrap_or_else(|_| panic!("invalid value {:?} in {:?}", value, line));
+
+		Sample {
+			name,
+			labels,
+			value,
+		}
+	}
+
+	fn test_state() -> AppState {
+		AppState::new(&Config::default())
+	}
+
+	fn device(device_id: &str, device_name: &str) -> DeviceMetrics {
+		DeviceMetrics {
+			site_id: "site-1".to_string(),
+			site_name: "Default".to_string(),
+			device_id: device_id.to_string(),
+			device_name: device_name.to_string(),
+			device_model: "U7PG2".to_string(),
+			ip_address: Some("10.0.0.2".to_string()),
+			cpu_usage: Some(12.5),
+			memory_usage: Some(40.0),
+			temperature: Some(45.0),
+			uplink_tx_rate: Some(1000.0),
+			uplink_rx_rate: Some(2000.0),
+			poe_budget: None,
+			poe_consumption: None,
+			radios: Vec::new(),
+			last_heartbeat: Some(Utc::now()),
+			state: 1,
+			state_raw: "ONLINE".to_string(),
+			last_updated: Instant::now(),
+			stale_fields: HashSet::new(),
+		}
+	}
+
+	fn sensor(sensor_id: &str, sensor_name: &str) -> SensorMetrics {
+		SensorMetrics {
+			sensor_id: sensor_id.to_string(),
+			sensor_name: sensor_name.to_string(),
+			mount_type: "door".to_string(),
+			temperature: Some(21.5),
+			humidity: Some(45.0),
+			light: Some(120.0),
+			battery: Some(90.0),
+			battery_low: Some(false),
+			battery_voltage: Some(3.0),
+			state: 1,
+			state_raw: "CONNECTED".to_string(),
+			motion_detected: Some(0),
+			is_opened: Some(1),
+			motion_enabled: Some(1),
+			alarm_enabled: None,
+			leak_enabled: None,
+			leak_detected: None,
+			tampering_detected: Some(0),
+			alarm_triggered: None,
+			last_seen: Instant::now(),
+			extra_labels: Vec::new(),
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn concurrent_scrapes_and_updates_do_not_deadlock() {
+		let _metrics = isolated_metrics().await;
+		let state = test_state();
+
+		let updates = tokio::spawn(async {
+			for i in 0..500 {
+				update_device_metrics(device(&format!("device-{}", i % 50), "Access Point"));
+				update_sensor_metrics(sensor(&format!("sensor-{}", i % 20), "Front Door"));
+				update_poll_metrics("network", i % 7 != 0, 0.2);
+				tokio::task::yield_now().await;
+			}
+		});
+
+		let scrapes: Vec<_> = (0..8)
+			.map(|_| {
+				let state = state.clone();
+				tokio::spawn(async move {
+					for _ in 0..50 {
+						let samples = parse(&generate_metrics_output(&state, Format::OpenMetrics));
+						let series = samples
+							.iter()
+							.find(|s| s.name == "unifi_exporter_series_total")
+							.expect("series count is always exported");
+						assert_eq!(series.value, samples.len() as f64);
+						assert!(
+							samples
+								.iter()
+								.filter_map(|s| s.label("device_name"))
+								.all(|name| name == "Access Point")
+						);
+						tokio::task::yield_now().await;
+					}
+				})
+			})
+			.collect();
+
+		tokio::time::timeout(Duration::from_secs(30), async {
+			updates.await.unwrap();
+			for scrape in scrapes {
+				scrape.await.unwrap();
+			}
+		})
+		.await
+		.expect("concurrent scrapes and updates deadlocked");
+	}
+
+	#[tokio::test]
+	async fn label_values_are_escaped_and_round_trip() {
+		let _metrics = isolated_metrics().await;
+		let name = "Office \"North\" AP\\core\nsecond line";
+		update_device_metrics(device("device-1", name));
+
+		let output = generate_metrics_output(&test_state(), Format::OpenMetrics);
+		assert!(output.contains(r#"device_name="Office \"North\" AP\\core\nsecond line""#));
+
+		let samples = parse(&output);
+		let state = samples
+			.iter()
+			.find(|s| s.name == "unifi_device_state")
+			.unwrap();
+		assert_eq!(state.label("device_name"), Some(name));
+	}
+
+	#[tokio::test]
+	async fn metrics_not_refreshed_within_ttl_are_evicted() {
+		let _metrics = isolated_metrics().await;
+		let state = test_state();
+		let ttl = Duration::from_secs(300);
+		let polled = Instant::now();
+
+		let mut stale = device("device-stale", "Old AP");
+		stale.last_updated = polled;
+		update_device_metrics(stale);
+		let mut fresh = device("device-fresh", "New AP");
+		fresh.last_updated = polled + Duration::from_secs(120);
+		update_device_metrics(fresh);
+		let mut gone = sensor("sensor-gone", "Garage");
+		gone.last_seen = polled;
+		update_sensor_metrics(gone);
+
+		// Nothing is evicted while every entry is within the TTL
+		evict_stale_metrics(ttl, polled + ttl);
+		let output = generate_metrics_output(&state, Format::OpenMetrics);
+		assert!(output.contains(r#"device_id="device-stale""#));
+		assert!(output.contains(r#"sensor_id="sensor-gone""#));
+
+		evict_stale_metrics(ttl, polled + ttl + Duration::from_secs(1));
+		let output = generate_metrics_output(&state, Format::OpenMetrics);
+		assert!(!output.contains(r#"device_id="device-stale""#));
+		assert!(!output.contains(r#"sensor_id="sensor-gone""#));
+		assert!(output.contains(r#"device_id="device-fresh""#));
+	}
+
+	fn accept(value: Option<&str>) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		if let Some(value) = value {
+			headers.insert(header::ACCEPT, value.parse().unwrap());
+		}
+		headers
+	}
+
+	#[test]
+	fn format_is_negotiated_from_accept_header() {
+		assert_eq!(Format::negotiate(&accept(None)), Format::OpenMetrics);
+		assert_eq!(
+			Format::negotiate(&accept(Some("text/plain; version=0.0.4"))),
+			Format::Prometheus
+		);
+		assert_eq!(
+			Format::negotiate(&accept(Some(
+				"application/openmetrics-text; version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+			))),
+			Format::OpenMetrics
+		);
+	}
+
+	#[tokio::test]
+	async fn unit_and_eof_are_only_written_for_openmetrics() {
+		let _metrics = isolated_metrics().await;
+		update_device_metrics(device("device-1", "Access Point"));
+		update_poll_metrics("network", true, 0.2);
+		let state = test_state();
+
+		let output = generate_metrics_output(&state, Format::OpenMetrics);
+		parse(&output);
+		assert!(output.contains("# UNIT unifi_device_temperature_celsius celsius\n"));
+		assert!(output.contains("# TYPE unifi_poll_skipped_ticks counter\n"));
+		assert!(output.ends_with("# EOF\n"));
+
+		let output = generate_metrics_output(&state, Format::Prometheus);
+		parse(&output);
+		assert!(!output.contains("# UNIT "));
+		assert!(!output.contains("# EOF"));
+		assert!(output.contains("# TYPE unifi_poll_skipped_ticks_total counter\n"));
+	}
+
+	#[tokio::test]
+	async fn only_name_labels_are_truncated() {
+		let _metrics = isolated_metrics().await;
+		let mut config = Config::default();
+		config.metrics.max_label_length = Some(8);
+		config.server.instance_label = Some("exporter-primary".to_string());
+		let state = AppState::new(&config);
+
+		let mut long = device("device-0123456789", "Conference Room AP");
+		long.state_raw = "PENDING_ADOPTION".to_string();
+		update_device_metrics(long);
+		let mut tagged = sensor("sensor-0123456789", "Server Room Door");
+		tagged.extra_labels = vec![("rack".to_string(), "Rack 12 Row B".to_string())];
+		update_sensor_metrics(tagged);
+		update_poll_metrics("protect_system", true, 0.2);
+
+		let samples = parse(&generate_metrics_output(&state, Format::OpenMetrics));
+		let device_state = samples
+			.iter()
+			.find(|s| s.name == "unifi_device_state")
+			.unwrap();
+		assert_eq!(device_state.label("device_name"), Some("Confere…"));
+		assert_eq!(device_state.label("device_id"), Some("device-0123456789"));
+		assert_eq!(device_state.label("state_raw"), Some("PENDING_ADOPTION"));
+		assert_eq!(device_state.label("instance"), Some("exporter-primary"));
+
+		let sensor_state = samples
+			.iter()
+			.find(|s| s.name == "unifi_sensor_state")
+			.unwrap();
+		assert_eq!(sensor_state.label("sensor_name"), Some("Server …"));
+		assert_eq!(sensor_state.label("rack"), Some("Rack 12…"));
+		assert_eq!(sensor_state.label("sensor_id"), Some("sensor-0123456789"));
+
+		let bucket = samples
+			.iter()
+			.find(|s| s.name == "unifi_poll_cycle_duration_seconds_bucket")
+			.unwrap();
+		assert_eq!(bucket.label("type"), Some("protect_system"));
+	}
+
+	#[tokio::test]
+	async fn control_characters_are_stripped_from_label_values() {
+		let _metrics = isolated_metrics().await;
+		update_device_metrics(device("device-1", "Lobby\u{0}\u{7}\r\tAP"));
+		update_sensor_metrics(sensor("sensor-1", "Back\u{1b}[31m Door"));
+
+		let samples = parse(&generate_metrics_output(&test_state(), Format::OpenMetrics));
+		let names: HashSet<&str> = samples
+			.iter()
+			.filter_map(|s| s.label("device_name").or(s.label("sensor_name")))
+			.collect();
+		assert_eq!(names, HashSet::from(["LobbyAP", "Back[31m Door"]));
+	}
+
+	fn render_non_finite(non_finite_values: NonFiniteValues) -> Vec<Sample> {
+		let mut nan = device("device-nan", "Switch");
+		nan.cpu_usage = Some(f64::NAN);
+		nan.temperature = Some(f64::INFINITY);
+		update_device_metrics(nan);
+
+		let mut config = Config::default();
+		config.metrics.non_finite_values = non_finite_values;
+		parse(&generate_metrics_output(
+			&AppState::new(&config),
+			Format::OpenMetrics,
+		))
+	}
+
+	#[tokio::test]
+	async fn non_finite_values_are_dropped() {
+		let _metrics = isolated_metrics().await;
+		let samples = render_non_finite(NonFiniteValues::Drop);
+
+		assert!(samples.iter().all(|s| s.value.is_finite()));
+		assert!(
+			!samples
+				.iter()
+				.any(|s| s.name == "unifi_device_cpu_usage_ratio")
+		);
+		assert!(
+			!samples
+				.iter()
+				.any(|s| s.name == "unifi_device_temperature_celsius")
+		);
+		// Finite values of the same device are still exported
+		assert!(
+			samples
+				.iter()
+				.any(|s| s.name == "unifi_device_memory_usage_ratio")
+		);
+	}
+
+	#[tokio::test]
+	async fn non_finite_values_are_clamped() {
+		let _metrics = isolated_metrics().await;
+		let samples = render_non_finite(NonFiniteValues::Clamp);
+		let value = |name: &str| samples.iter().find(|s| s.name == name).unwrap().value;
+
+		assert!(samples.iter().all(|s| s.value.is_finite()));
+		assert_eq!(value("unifi_device_cpu_usage_ratio"), 0.0);
+		assert_eq!(value("unifi_device_temperature_celsius"), f64::MAX);
+	}
+
+	#[tokio::test]
+	async fn metrics_of_removed_sites_and_devices_are_purged() {
+		let _metrics = isolated_metrics().await;
+		for site_id in ["site-1", "site-2"] {
+			update_site_metrics(SiteMetrics {
+				site_id: site_id.to_string(),
+				site_name: site_id.to_string(),
+				device_count: 2,
+			});
+			for device_id in ["device-1", "device-2"] {
+				let mut metrics = device(device_id, device_id);
+				metrics.site_id = site_id.to_string();
+				update_device_metrics(metrics);
+			}
+		}
+
+		remove_site_metrics("site-2");
+		remove_device_metrics("site-1", &HashSet::from(["device-2".to_string()]));
+
+		let samples = parse(&generate_metrics_output(&test_state(), Format::OpenMetrics));
+		let series: HashSet<(&str, Option<&str>)> = samples
+			.iter()
+			.filter(|s| s.name == "unifi_site_device_count" || s.name == "unifi_device_state")
+			.map(|s| (s.label("site_id").unwrap(), s.label("device_id")))
+			.collect();
+		assert_eq!(
+			series,
+			HashSet::from([("site-1", None), ("site-1", Some("device-1"))])
+		);
+	}
+
+	#[tokio::test]
+	async fn radios_with_shared_or_missing_frequencies_get_distinct_series() {
+		let _metrics = isolated_metrics().await;
+		let mut access_point = device("device-1", "Access Point");
+		access_point.radios = [Some("5"), Some("5"), None]
+			.into_iter()
+			.enumerate()
+			.map(|(i, frequency)| RadioMetrics {
+				radio: Some(i.to_string()),
+				frequency_ghz: frequency.map(str::to_string),
+				tx_retries: 1.0,
+			})
+			.collect();
+		update_device_metrics(access_point);
+
+		let samples = parse(&generate_metrics_output(&test_state(), Format::OpenMetrics));
+		let radios: HashSet<(Option<&str>, Option<&str>)> = samples
+			.iter()
+			.filter(|s| s.name == "unifi_device_radio_tx_retries_ratio")
+			.map(|s| (s.label("radio"), s.label("frequency_ghz")))
+			.collect();
+		assert_eq!(
+			radios,
+			HashSet::from([
+				(Some("0"), Some("5")),
+				(Some("1"), Some("5")),
+				(Some("2"), None)
+			])
+		);
+	}
+}
@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::{PoisonError, RwLock};
 
 use super::models::{CachedDevice, CachedSite, Device, Site};
 
@@ -14,26 +14,50 @@ impl UnifiCache {
 		}
 	}
 
-	pub fn update_sites(&self, sites: Vec<Site>) {
-		let mut cache = self.sites.write().unwrap();
+	// Replace the cached sites with the latest API response, returning the IDs of sites that disappeared
+	pub fn update_sites(&self, sites: Vec<Site>) -> Vec<String> {
+		let mut cache = self.sites.write().unwrap_or_else(PoisonError::into_inner);
+
+		let current: HashSet<&str> = sites.iter().map(|s| s.id.as_str()).collect();
+		let removed: Vec<String> = cache
+			.keys()
+			.filter(|id| !current.contains(id.as_str()))
+			.cloned()
+			.collect();
+		for id in &removed {
+			cache.remove(id);
+		}
+
 		for site in sites {
-			cache.entry(site.id.clone()).or_insert_with(|| CachedSite {
-				id: site.id.clone(),
-				name: site.name.clone(),
-				devices: HashMap::new(),
-			});
+			cache
+				.entry(site.id.clone())
+				.and_modify(|cached| cached.name = site.name.clone())
+				.or_insert_with(|| CachedSite {
+					id: site.id.clone(),
+					name: site.name.clone(),
+					devices: HashMap::new(),
+					devices_loaded: false,
+				});
 		}
+
+		removed
 	}
 
+	// Replace the site's devices with the latest API response, dropping devices that disappeared
 	pub fn update_devices(&self, site_id: &str, devices: Vec<Device>) {
-		let mut cache = self.sites.write().unwrap();
+		let mut cache = self.sites.write().unwrap_or_else(PoisonError::into_inner);
 		if let Some(site) = cache.get_mut(site_id) {
+			let current: HashSet<&str> = devices.iter().map(|d| d.id.as_str()).collect();
+			site.devices.retain(|id, _| current.contains(id.as_str()));
+			site.devices_loaded = true;
+
 			for device in devices {
 				site.devices.insert(
 					device.id.clone(),
 					CachedDevice {
 						name: device.name,
 						model: device.model,
+						mac_address: device.mac_address,
 						ip_address: device.ip_address,
 						state: device.state,
 					},
@@ -42,8 +66,17 @@ impl UnifiCache {
 		}
 	}
 
+	// IDs of the site's cached devices, None until its devices have been fetched
+	pub fn device_ids(&self, site_id: &str) -> Option<HashSet<String>> {
+		let cache = self.sites.read().unwrap_or_else(PoisonError::into_inner);
+		cache
+			.get(site_id)
+			.filter(|site| site.devices_loaded)
+			.map(|site| site.devices.keys().cloned().collect())
+	}
+
 	pub fn get_sites(&self) -> Vec<CachedSite> {
-		let cache = self.sites.read().unwrap();
+		let cache = self.sites.read().unwrap_or_else(PoisonError::into_inner);
 		cache.values().cloned().collect()
 	}
 }
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Additive-increase/multiplicative-decrease limit for concurrent API requests
+pub struct AdaptiveConcurrency {
+	max: usize,
+	current: AtomicUsize,
+}
+
+impl AdaptiveConcurrency {
+	pub fn new(max: usize) -> Self {
+		let max = max.max(1);
+		Self {
+			max,
+			current: AtomicUsize::new(max),
+		}
+	}
+
+	pub fn current(&self) -> usize {
+		self.current.load(Ordering::Relaxed)
+	}
+
+	// Halve the limit after a cycle where most requests failed, otherwise grow it by one up to
+	// the maximum. Isolated failures, like a single offline device, don't signal an overloaded
+	// controller and must not keep the limit down.
+	pub fn record_cycle(&self, failed: usize, total: usize) -> usize {
+		let current = self.current();
+		let next = if failed * 2 > total {
+			(current / 2).max(1)
+		} else {
+			(current + 1).min(self.max)
+		};
+		self.current.store(next, Ordering::Relaxed);
+		next
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn limit_recovers_while_a_single_device_keeps_failing() {
+		let concurrency = AdaptiveConcurrency::new(8);
+
+		// A controller failing most requests halves the limit
+		assert_eq!(concurrency.record_cycle(10, 12), 4);
+		assert_eq!(concurrency.record_cycle(12, 12), 2);
+
+		// One device failing every cycle while the rest succeed lets it grow back to the maximum
+		for _ in 0..10 {
+			concurrency.record_cycle(1, 12);
+		}
+		assert_eq!(concurrency.current(), 8);
+	}
+}
@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use super::network::NetworkClient;
+use super::protect::ProtectClient;
+
+// Print raw API responses to stdout for troubleshooting (--dump-api)
+pub async fn dump_api(
+	network: &NetworkClient,
+	protect: &ProtectClient,
+	endpoint: &str,
+) -> Result<()> {
+	match endpoint {
+		"sites" => println!("{}", network.fetch_raw("/sites?limit=25").await?),
+		"devices" | "statistics" => {
+			let sites = network.fetch_raw("/sites?limit=25").await?;
+			let site_ids = data_ids(&sites).context("Failed to parse sites response")?;
+
+			for site_id in site_ids {
+				let devices = network
+					.fetch_raw(&format!("/sites/{}/devices?limit=200", site_id))
+					.await?;
+
+				if endpoint == "devices" {
+					println!("{}", devices);
+					continue;
+				}
+
+				let device_ids = data_ids(&devices).context("Failed to parse devices response")?;
+
+				for device_id in device_ids {
+					let path = format!("/sites/{}/devices/{}/statistics/latest", site_id, device_id);
+					println!("{}", network.fetch_raw(&path).await?);
+				}
+			}
+		}
+		"sensors" => println!("{}", protect.fetch_raw("/sensors").await?),
+		other => anyhow::bail!(
+			"Unknown --dump-api endpoint '{}', expected one of: sites, devices, sensors, statistics",
+			other
+		),
+	}
+
+	Ok(())
+}
+
+// Collect data[].id from a list response without the full models, so a dump still works
+// when other fields don't match what the exporter expects
+fn data_ids(body: &str) -> Result<Vec<String>> {
+	let value: Value = serde_json::from_str(body)?;
+	let data = value
+		.get("data")
+		.and_then(Value::as_array)
+		.context("Missing data array")?;
+
+	let ids = data
+		.iter()
+		.filter_map(|item| item.get("id").and_then(Value::as_str))
+		.map(str::to_string)
+		.collect();
+
+	Ok(ids)
+}
@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use log::debug;
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::future::Future;
+use tokio::time::{Duration, sleep};
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+// Returned when a console doesn't have the requested UniFi application installed
+#[derive(Debug)]
+pub struct NotInstalled(pub &'static str);
+
+impl fmt::Display for NotInstalled {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "UniFi {} is not installed on this console", self.0)
+	}
+}
+
+impl std::error::Error for NotInstalled {}
+
+// Decode a JSON response body, replacing invalid UTF-8 sequences instead of failing
+pub async fn read_json<T: DeserializeOwned>(response: Response) -> Result<T> {
+	let bytes = response
+		.bytes()
+		.await
+		.context("Failed to read response body")?;
+	let body = String::from_utf8_lossy(&bytes);
+
+	Ok(serde_json::from_str::<T>(&body)?)
+}
+
+// Fetch a URL and return the body unparsed, logging the exchange to stderr for --dump-api
+pub async fn fetch_raw(client: &Client, url: &str, api_token: &str, api: &str) -> Result<String> {
+	eprintln!("GET {} (X-API-KEY: <redacted>)", url);
+
+	let response = client
+		.get(url)
+		.header("X-API-KEY", api_token)
+		.header("Accept", "application/json")
+		.send()
+		.await
+		.with_context(|| format!("Failed to send request to {}", api))?;
+
+	eprintln!("HTTP {}", response.status());
+
+	response
+		.text()
+		.await
+		.context("Failed to read response body")
+}
+
+// Run a request, retrying failures with exponential backoff
+pub async fn with_retry<T, F, Fut>(retries: u32, description: &str, mut request: F) -> Result<T>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<T>>,
+{
+	let mut attempt = 0;
+
+	loop {
+		match request().await {
+			Ok(value) => return Ok(value),
+			Err(e) if attempt < retries => {
+				attempt += 1;
+				let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+				debug!(
+					"{} failed (attempt {}/{}), retrying in {:?}: {:#}",
+					description,
+					attempt,
+					retries + 1,
+					delay,
+					e
+				);
+				sleep(delay).await;
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
@@ -1,8 +1,13 @@
 mod cache;
+mod concurrency;
+mod dump;
+mod http;
 mod models;
 mod network;
 mod protect;
+mod state;
 
 pub use cache::UnifiCache;
+pub use dump::dump_api;
 pub use network::NetworkClient;
 pub use protect::ProtectClient;
@@ -76,8 +76,11 @@ pub struct DeviceStatistics {
 	pub cpu_utilization_pct: Option<f64>,
 	#[serde(rename = "memoryUtilizationPct")]
 	pub memory_utilization_pct: Option<f64>,
+	#[serde(rename = "temperatureCelsius", alias = "generalTemperature")]
+	pub temperature_celsius: Option<f64>,
 	pub uplink: Option<UplinkStats>,
 	pub interfaces: Option<InterfaceStats>,
+	pub poe: Option<PoeStats>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -88,6 +91,14 @@ pub struct UplinkStats {
 	pub rx_rate_bps: Option<f64>,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PoeStats {
+	#[serde(rename = "budgetW")]
+	pub budget_w: Option<f64>,
+	#[serde(rename = "consumptionW")]
+	pub consumption_w: Option<f64>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct InterfaceStats {
 	pub radios: Option<Vec<RadioStats>>,
@@ -149,6 +160,8 @@ pub struct BatteryStatus {
 	pub percentage: Option<f64>,
 	#[serde(rename = "isLow")]
 	pub is_low: Option<bool>,
+	// Only reported by some sensor models
+	pub voltage: Option<f64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -196,18 +209,58 @@ pub struct LeakSettings {
 	pub is_external_enabled: Option<bool>,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Nvr {
+	pub id: String,
+	pub name: Option<String>,
+	#[serde(rename = "modelKey")]
+	pub model_key: String,
+	#[serde(rename = "systemInfo")]
+	pub system_info: Option<NvrSystemInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NvrSystemInfo {
+	pub cpu: Option<NvrCpuInfo>,
+	pub memory: Option<NvrMemoryInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NvrCpuInfo {
+	#[serde(rename = "averageLoad")]
+	pub average_load: Option<f64>,
+	pub temperature: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NvrMemoryInfo {
+	pub available: Option<f64>,
+	pub free: Option<f64>,
+	pub total: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Camera {
+	pub id: String,
+	pub name: String,
+	pub state: String,
+}
+
 // Cache structures
 #[derive(Debug, Clone)]
 pub struct CachedSite {
 	pub id: String,
 	pub name: String,
 	pub devices: HashMap<String, CachedDevice>,
+	// Whether the site's devices have been fetched at least once
+	pub devices_loaded: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct CachedDevice {
 	pub name: String,
 	pub model: String,
+	pub mac_address: String,
 	pub ip_address: Option<String>,
 	pub state: String,
 }
@@ -1,104 +1,341 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
 use log::{debug, error, info, warn};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
 
 use super::cache::UnifiCache;
-use super::models::{ApiError, DeviceStatistics, DevicesResponse, SitesResponse};
-use crate::metrics::{update_device_metrics, update_poll_metrics};
+use super::concurrency::AdaptiveConcurrency;
+use super::http::{NotInstalled, fetch_raw, read_json, with_retry};
+use super::models::{
+	ApiError, CachedDevice, CachedSite, DeviceStatistics, DevicesResponse, SitesResponse,
+};
+use super::state::device_state;
+use crate::config::{Config, IpAddressFallback, RadioAggregation};
+use crate::metrics::{
+	DeviceMetrics, RadioMetrics, SiteMetrics, record_discovery_delta, remove_device_metrics,
+	remove_site_metrics, update_availability, update_device_metrics, update_effective_concurrency,
+	update_poll_metrics, update_site_metrics, update_too_old_devices,
+};
+
+// Outcome of polling the statistics of a single device
+#[derive(PartialEq)]
+enum DeviceOutcome {
+	Updated,
+	// Older than monitoring.max_data_age_secs, skipped without failing the poll
+	TooOld,
+	Failed,
+}
 
 pub struct NetworkClient {
 	client: Client,
 	base_url: String,
 	api_token: String,
+	critical_devices: Vec<String>,
+	ip_address_fallback: IpAddressFallback,
+	radio_aggregation: RadioAggregation,
+	max_data_age_secs: Option<u64>,
+	request_retries: u32,
+	concurrency: AdaptiveConcurrency,
+	available: AtomicBool,
+	// Whether the last site discovery completed, polls fail until one does
+	discovered: AtomicBool,
 }
 
 impl NetworkClient {
-	pub fn new(client: Client, ip: String, api_token: String) -> Self {
+	pub fn new(client: Client, config: &Config) -> Self {
 		Self {
 			client,
-			base_url: format!("https://{}/proxy/network/integration/v1", ip),
-			api_token,
+			base_url: format!("https://{}/proxy/network/integration/v1", config.unifi.ip),
+			api_token: config.unifi.api_token.clone(),
+			critical_devices: config.monitoring.critical_devices.clone(),
+			ip_address_fallback: config.metrics.ip_address_fallback,
+			radio_aggregation: config.monitoring.radio_aggregation,
+			max_data_age_secs: config.monitoring.max_data_age_secs,
+			request_retries: config.unifi.request_retries,
+			concurrency: AdaptiveConcurrency::new(config.unifi.max_concurrent_requests),
+			available: AtomicBool::new(true),
+			discovered: AtomicBool::new(false),
+		}
+	}
+
+	// Critical devices can be referenced by either their ID or name
+	fn is_critical(&self, device_id: &str, device: &CachedDevice) -> bool {
+		self
+			.critical_devices
+			.iter()
+			.any(|c| c == device_id || *c == device.name)
+	}
+
+	fn ip_address_label(&self, device: &CachedDevice) -> Option<String> {
+		match (&device.ip_address, self.ip_address_fallback) {
+			(Some(ip), _) => Some(ip.clone()),
+			(None, IpAddressFallback::Unknown) => Some("unknown".to_string()),
+			(None, IpAddressFallback::Mac) => Some(device.mac_address.clone()),
+			(None, IpAddressFallback::Omit) => None,
+		}
+	}
+
+	fn radio_metrics(&self, stats: &DeviceStatistics) -> Vec<RadioMetrics> {
+		let radios = stats
+			.interfaces
+			.as_ref()
+			.and_then(|i| i.radios.as_ref())
+			.into_iter()
+			.flatten()
+			.enumerate()
+			.filter_map(|(index, radio)| {
+				radio.tx_retries_pct.map(|tx_retries| RadioMetrics {
+					radio: Some(index.to_string()),
+					frequency_ghz: radio.frequency_ghz.map(|f| f.to_string()),
+					tx_retries,
+				})
+			});
+
+		match self.radio_aggregation {
+			RadioAggregation::PerRadio => radios.collect(),
+			// Report the worst radio so a single congested band isn't averaged away
+			RadioAggregation::PerDevice => radios
+				.map(|radio| radio.tx_retries)
+				.reduce(f64::max)
+				.map(|tx_retries| RadioMetrics {
+					radio: None,
+					frequency_ghz: None,
+					tx_retries,
+				})
+				.into_iter()
+				.collect(),
 		}
 	}
 
+	// Network is installed but the last site discovery didn't complete
+	pub fn discovery_failed(&self) -> bool {
+		self.available.load(Ordering::Relaxed) && !self.discovered.load(Ordering::Relaxed)
+	}
+
 	pub async fn initialize_sites(&self, cache: &UnifiCache) -> Result<()> {
-		// Fetch all sites
-		let sites = self.get_sites().await?;
+		// Fetch all sites, disabling polling if the console doesn't run Network
+		let sites = match self.get_sites().await {
+			Ok(sites) => sites,
+			Err(e) if e.downcast_ref::<NotInstalled>().is_some() => {
+				if self.available.swap(false, Ordering::Relaxed) {
+					warn!("{}, disabling Network polling", e);
+				}
+				update_availability("network", false);
+				return Ok(());
+			}
+			Err(e) => {
+				self.discovered.store(false, Ordering::Relaxed);
+				return Err(e);
+			}
+		};
 		info!("Found {} sites", sites.data.len());
+		self.available.store(true, Ordering::Relaxed);
+		update_availability("network", true);
 
-		// Update cache with sites
-		cache.update_sites(sites.data.clone());
+		// Update cache with sites, dropping the metrics of sites that no longer exist
+		for site_id in cache.update_sites(sites.data.clone()) {
+			info!("Site {} no longer exists, removing its metrics", site_id);
+			remove_site_metrics(&site_id);
+		}
 
 		// Fetch devices for each site
+		let mut complete = true;
 		for site in &sites.data {
 			info!("Fetching devices for site: {} ({})", site.name, site.id);
-			match self.get_devices(&site.id).await {
+			let description = format!("Fetching devices for site {}", site.name);
+			let devices = with_retry(self.request_retries, &description, || {
+				self.get_devices(&site.id)
+			})
+			.await;
+
+			match devices {
 				Ok(devices) => {
 					info!("Found {} devices in site {}", devices.data.len(), site.name);
+
+					let before = cache.device_ids(&site.id);
 					cache.update_devices(&site.id, devices.data);
+					let after = cache.device_ids(&site.id).unwrap_or_default();
+
+					// The first discovery of a site finds its existing devices, which aren't a delta
+					if let Some(before) = before {
+						let added = after.difference(&before).count();
+						let removed: HashSet<String> = before.difference(&after).cloned().collect();
+						remove_device_metrics(&site.id, &removed);
+						if added > 0 || !removed.is_empty() {
+							info!(
+								"Site {}: {} devices added, {} removed since last discovery",
+								site.name,
+								added,
+								removed.len()
+							);
+						}
+						record_discovery_delta(added, removed.len());
+					}
 				}
 				Err(e) => {
-					error!("Failed to fetch devices for site {}: {}", site.name, e);
+					error!("Failed to fetch devices for site {}: {:#}", site.name, e);
+					complete = false;
 				}
 			}
 		}
 
+		self.discovered.store(complete, Ordering::Relaxed);
+
+		// Summarize the discovered inventory
+		for site in cache.get_sites() {
+			info!("Site {}: {} devices", site.name, site.devices.len());
+			update_site_metrics(SiteMetrics {
+				site_id: site.id.clone(),
+				site_name: site.name.clone(),
+				device_count: site.devices.len(),
+			});
+		}
+
 		Ok(())
 	}
 
 	pub async fn poll_statistics(&self, cache: &UnifiCache) -> Result<()> {
+		if !self.available.load(Ordering::Relaxed) {
+			return Ok(());
+		}
+
 		let start = Instant::now();
-		let mut success = true;
 
 		let sites = cache.get_sites();
 
-		for site in sites {
-			for (device_id, device) in &site.devices {
-				debug!(
-					"Polling statistics for device {} ({}) in site {}",
-					device.name, device_id, site.name
-				);
+		// Poll critical devices first so their metrics are freshest on long cycles
+		let mut devices: Vec<(&CachedSite, &String, &CachedDevice)> = sites
+			.iter()
+			.flat_map(|site| {
+				site
+					.devices
+					.iter()
+					.map(move |(device_id, device)| (site, device_id, device))
+			})
+			.collect();
+		devices.sort_by_key(|(_, device_id, device)| !self.is_critical(device_id, device));
 
-				match self.get_device_statistics(&site.id, device_id).await {
-					Ok(stats) => {
-						// Determine device state
-						let state_value = if device.state.to_uppercase() == "ONLINE" {
-							1
-						} else {
-							0
-						};
-
-						// Update metrics
-						update_device_metrics(
-							&site.id,
-							&site.name,
-							device_id,
-							&device.name,
-							&device.model,
-							device.ip_address.as_deref().unwrap_or("unknown"),
-							stats.cpu_utilization_pct,
-							stats.memory_utilization_pct,
-							stats.uplink.as_ref().and_then(|u| u.tx_rate_bps),
-							stats.uplink.as_ref().and_then(|u| u.rx_rate_bps),
-							state_value,
-						);
-					}
-					Err(e) => {
+		// Fan out requests, backing off when the controller starts failing
+		let limit = self.concurrency.current();
+		let requests: Vec<_> = devices
+			.into_iter()
+			.map(|(site, device_id, device)| self.poll_device(site, device_id, device))
+			.collect();
+		let results: Vec<DeviceOutcome> = stream::iter(requests)
+			.buffer_unordered(limit)
+			.collect()
+			.await;
+
+		// Without a complete discovery the cache is stale or empty, so the poll can't be trusted
+		let discovered = self.discovered.load(Ordering::Relaxed);
+		if !discovered {
+			warn!("Last network site discovery failed, marking the poll as failed");
+		}
+
+		// Too old statistics are a controller issue, so they don't back off the request rate
+		let failed = results
+			.iter()
+			.filter(|outcome| **outcome == DeviceOutcome::Failed)
+			.count();
+		let success = discovered && failed == 0;
+		let next_limit = self.concurrency.record_cycle(failed, results.len());
+		if next_limit != limit {
+			info!(
+				"Adjusted network poll concurrency from {} to {}",
+				limit, next_limit
+			);
+		}
+		update_effective_concurrency(next_limit);
+
+		if self.max_data_age_secs.is_some() {
+			let too_old = results
+				.iter()
+				.filter(|outcome| **outcome == DeviceOutcome::TooOld)
+				.count();
+			update_too_old_devices(too_old);
+		}
+
+		let duration = start.elapsed().as_secs_f64();
+		update_poll_metrics("network", success, duration);
+
+		Ok(())
+	}
+
+	async fn poll_device(
+		&self,
+		site: &CachedSite,
+		device_id: &str,
+		device: &CachedDevice,
+	) -> DeviceOutcome {
+		debug!(
+			"Polling statistics for device {} ({}) in site {}",
+			device.name, device_id, site.name
+		);
+
+		match self.get_device_statistics(&site.id, device_id).await {
+			Ok(stats) => {
+				let last_heartbeat = stats
+					.last_heartbeat_at
+					.as_deref()
+					.and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+					.map(|t| t.with_timezone(&Utc));
+
+				// Skip statistics the controller hasn't refreshed in too long
+				if let (Some(max_age), Some(heartbeat)) = (self.max_data_age_secs, last_heartbeat) {
+					let age = (Utc::now() - heartbeat).num_seconds();
+					if age > max_age as i64 {
 						warn!(
-							"Failed to fetch statistics for device {} in site {}: {}",
-							device.name, site.name, e
+							"Statistics for device {} in site {} are {}s old (max {}s), skipping",
+							device.name, site.name, age, max_age
 						);
-						success = false;
+						return DeviceOutcome::TooOld;
 					}
 				}
+
+				// Update metrics
+				update_device_metrics(DeviceMetrics {
+					site_id: site.id.clone(),
+					site_name: site.name.clone(),
+					device_id: device_id.to_string(),
+					device_name: device.name.clone(),
+					device_model: device.model.clone(),
+					ip_address: self.ip_address_label(device),
+					cpu_usage: stats.cpu_utilization_pct,
+					memory_usage: stats.memory_utilization_pct,
+					temperature: stats.temperature_celsius,
+					uplink_tx_rate: stats.uplink.as_ref().and_then(|u| u.tx_rate_bps),
+					uplink_rx_rate: stats.uplink.as_ref().and_then(|u| u.rx_rate_bps),
+					poe_budget: stats.poe.as_ref().and_then(|p| p.budget_w),
+					poe_consumption: stats.poe.as_ref().and_then(|p| p.consumption_w),
+					radios: self.radio_metrics(&stats),
+					last_heartbeat,
+					state: device_state(&device.state),
+					state_raw: device.state.clone(),
+					last_updated: Instant::now(),
+					stale_fields: HashSet::new(),
+				});
+
+				DeviceOutcome::Updated
 			}
-		}
+			Err(e) => {
+				warn!(
+					"Failed to fetch statistics for device {} in site {}: {:#}",
+					device.name, site.name, e
+				);
 
-		let duration = start.elapsed().as_secs_f64();
-		update_poll_metrics("network", success, duration);
+				DeviceOutcome::Failed
+			}
+		}
+	}
 
-		Ok(())
+	// Fetch an endpoint without parsing it, used by --dump-api
+	pub async fn fetch_raw(&self, path: &str) -> Result<String> {
+		let url = format!("{}{}", self.base_url, path);
+		fetch_raw(&self.client, &url, &self.api_token, "UniFi Network API").await
 	}
 
 	async fn get_sites(&self) -> Result<SitesResponse> {
@@ -113,6 +350,10 @@ impl NetworkClient {
 			.await
 			.context("Failed to send request to UniFi Network API")?;
 
+		if response.status() == StatusCode::NOT_FOUND {
+			return Err(NotInstalled("Network").into());
+		}
+
 		if !response.status().is_success() {
 			let status = response.status();
 			let error_text = response.text().await.unwrap_or_default();
@@ -130,8 +371,7 @@ impl NetworkClient {
 			anyhow::bail!("UniFi Network API error ({}): {}", status, error_text);
 		}
 
-		response
-			.json::<SitesResponse>()
+		read_json::<SitesResponse>(response)
 			.await
 			.context("Failed to parse sites response")
 	}
@@ -154,8 +394,7 @@ impl NetworkClient {
 			anyhow::bail!("Failed to get devices ({}): {}", status, error_text);
 		}
 
-		response
-			.json::<DevicesResponse>()
+		read_json::<DevicesResponse>(response)
 			.await
 			.context("Failed to parse devices response")
 	}
@@ -189,9 +428,137 @@ impl NetworkClient {
 			);
 		}
 
-		response
-			.json::<DeviceStatistics>()
+		read_json::<DeviceStatistics>(response)
 			.await
 			.context("Failed to parse device statistics")
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::metrics::{isolated_metrics, poll_status};
+	use axum::Router;
+	use axum::extract::Path;
+	use axum::routing::get;
+	use std::time::Duration;
+	use tokio::net::TcpListener;
+
+	const STATISTICS_DELAY: Duration = Duration::from_millis(200);
+
+	// Serve one site with the given number of devices, delaying every statistics response.
+	// Statistics requests for the failing device get a 500 response.
+	async fn mock_controller(
+		devices: usize,
+		statistics: String,
+		failing_device: Option<usize>,
+	) -> String {
+		let failing_device = failing_device.map(|i| format!("device-{}", i));
+		let device_list = (0..devices)
+			.map(|i| {
+				format!(
+					r#"{{"id":"device-{i}","name":"AP {i}","model":"U6LR","macAddress":"00:00:00:00:00:{i:02x}","state":"ONLINE"}}"#
+				)
+			})
+			.collect::<Vec<_>>()
+			.join(",");
+		let devices_body = format!(r#"{{"data":[{}]}}"#, device_list);
+
+		let app = Router::new()
+			.route(
+				"/proxy/network/integration/v1/sites",
+				get(|| async { r#"{"data":[{"id":"site-1","name":"Default"}]}"# }),
+			)
+			.route(
+				"/proxy/network/integration/v1/sites/{site_id}/devices",
+				get(move || async move { devices_body }),
+			)
+			.route(
+				"/proxy/network/integration/v1/sites/{site_id}/devices/{device_id}/statistics/latest",
+				get(
+					move |Path((_, device_id)): Path<(String, String)>| async move {
+						tokio::time::sleep(STATISTICS_DELAY).await;
+						if failing_device.as_deref() == Some(device_id.as_str()) {
+							(StatusCode::INTERNAL_SERVER_ERROR, String::new())
+						} else {
+							(StatusCode::OK, statistics)
+						}
+					},
+				),
+			);
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+		format!("http://{}/proxy/network/integration/v1", address)
+	}
+
+	#[tokio::test]
+	async fn device_statistics_are_fetched_concurrently() {
+		let _metrics = isolated_metrics().await;
+		let devices = 12;
+		let max_concurrent_requests = 4;
+
+		let mut config = Config::default();
+		config.unifi.max_concurrent_requests = max_concurrent_requests;
+		let mut client = NetworkClient::new(Client::new(), &config);
+		client.base_url =
+			mock_controller(devices, r#"{"cpuUtilizationPct":10.0}"#.to_string(), None).await;
+
+		let cache = UnifiCache::new();
+		client.initialize_sites(&cache).await.unwrap();
+
+		let start = Instant::now();
+		client.poll_statistics(&cache).await.unwrap();
+		let elapsed = start.elapsed();
+
+		// ceil(12 / 4) = 3 rounds of requests, far below the 12 a sequential poll would take
+		let rounds = devices.div_ceil(max_concurrent_requests) as u32;
+		assert!(elapsed >= STATISTICS_DELAY * rounds, "{:?}", elapsed);
+		assert!(elapsed < STATISTICS_DELAY * (rounds + 2), "{:?}", elapsed);
+		assert!(poll_status("network").unwrap().success);
+	}
+
+	#[tokio::test]
+	async fn too_old_statistics_do_not_fail_the_poll() {
+		let _metrics = isolated_metrics().await;
+		let mut config = Config::default();
+		config.monitoring.max_data_age_secs = Some(300);
+		let max_concurrent_requests = config.unifi.max_concurrent_requests;
+
+		let heartbeat = (Utc::now() - chrono::Duration::hours(1)).to_rfc3339();
+		let statistics = format!(r#"{{"lastHeartbeatAt":"{}"}}"#, heartbeat);
+		let mut client = NetworkClient::new(Client::new(), &config);
+		client.base_url = mock_controller(4, statistics, None).await;
+
+		let cache = UnifiCache::new();
+		client.initialize_sites(&cache).await.unwrap();
+		client.poll_statistics(&cache).await.unwrap();
+
+		assert!(poll_status("network").unwrap().success);
+		assert_eq!(client.concurrency.current(), max_concurrent_requests);
+	}
+
+	#[tokio::test]
+	async fn a_single_failing_device_does_not_hold_concurrency_down() {
+		let _metrics = isolated_metrics().await;
+		let mut config = Config::default();
+		config.unifi.max_concurrent_requests = 4;
+		let mut client = NetworkClient::new(Client::new(), &config);
+		client.base_url = mock_controller(4, "{}".to_string(), Some(0)).await;
+
+		// Start from a limit backed off by an earlier outage
+		client.concurrency.record_cycle(4, 4);
+		client.concurrency.record_cycle(4, 4);
+		assert_eq!(client.concurrency.current(), 1);
+
+		let cache = UnifiCache::new();
+		client.initialize_sites(&cache).await.unwrap();
+		for _ in 0..3 {
+			client.poll_statistics(&cache).await.unwrap();
+			assert!(!poll_status("network").unwrap().success);
+		}
+		assert_eq!(client.concurrency.current(), 4);
+	}
+}
@@ -1,40 +1,121 @@
 use anyhow::{Context, Result};
-use log::{debug, error, info};
-use reqwest::Client;
-use std::time::Instant;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use reqwest::{Client, StatusCode};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
-use super::models::{ApiError, Sensor};
-use crate::metrics::{update_poll_metrics, update_sensor_metrics};
+use super::http::{NotInstalled, fetch_raw, read_json};
+use super::models::{ApiError, Camera, Nvr, Sensor};
+use super::state::protect_state;
+use crate::config::{Config, SensorMissingBehavior};
+use crate::metrics::{
+	NvrMetrics, SensorMetrics, handle_missing_sensors, update_availability, update_nvr_metrics,
+	update_poll_metrics, update_sensor_metrics,
+};
+
+// How long a leak, tampering or alarm event keeps its detection metric at 1
+const SENSOR_EVENT_WINDOW_SECS: i64 = 300;
 
 pub struct ProtectClient {
 	client: Client,
 	base_url: String,
 	api_token: String,
+	sensor_metadata: HashMap<String, BTreeMap<String, String>>,
+	sensor_missing_behavior: SensorMissingBehavior,
+	// When Protect was last found missing, None while it is installed
+	not_installed_at: Mutex<Option<Instant>>,
+	reprobe_interval: Duration,
 }
 
 impl ProtectClient {
-	pub fn new(client: Client, ip: String, api_token: String) -> Self {
+	pub fn new(client: Client, config: &Config) -> Self {
 		Self {
 			client,
-			base_url: format!("https://{}/proxy/protect/integration/v1", ip),
-			api_token,
+			base_url: format!("https://{}/proxy/protect/integration/v1", config.unifi.ip),
+			api_token: config.unifi.api_token.clone(),
+			sensor_metadata: config.monitoring.sensor_metadata.clone(),
+			sensor_missing_behavior: config.monitoring.sensor_missing_behavior,
+			not_installed_at: Mutex::new(None),
+			reprobe_interval: Duration::from_secs(config.unifi.discovery_interval),
+		}
+	}
+
+	// Skip polls while Protect is missing, probing for it again once per discovery interval
+	fn should_poll(&self) -> bool {
+		match *self
+			.not_installed_at
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+		{
+			None => true,
+			Some(at) => !self.reprobe_interval.is_zero() && at.elapsed() >= self.reprobe_interval,
+		}
+	}
+
+	fn mark_installed(&self) {
+		let was_missing = self
+			.not_installed_at
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.take()
+			.is_some();
+		if was_missing {
+			info!("UniFi Protect is now installed, resuming Protect polling");
+		}
+		update_availability("protect", true);
+	}
+
+	// Pause polling while the console doesn't have Protect installed
+	fn check_installed(&self, error: &anyhow::Error) -> bool {
+		if error.downcast_ref::<NotInstalled>().is_none() {
+			return true;
+		}
+
+		let mut not_installed_at = self
+			.not_installed_at
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		if not_installed_at.replace(Instant::now()).is_none() {
+			if self.reprobe_interval.is_zero() {
+				warn!("{}, disabling Protect polling", error);
+			} else {
+				warn!(
+					"{}, pausing Protect polling and checking again every {}s",
+					error,
+					self.reprobe_interval.as_secs()
+				);
+			}
+			update_availability("protect", false);
 		}
+
+		false
 	}
 
 	pub async fn poll_sensors(&self) -> Result<()> {
+		if !self.should_poll() {
+			return Ok(());
+		}
+
 		let start = Instant::now();
 		let mut success = true;
 
 		match self.get_sensors().await {
 			Ok(sensors) => {
 				info!("Found {} sensors", sensors.len());
+				self.mark_installed();
 
-				for sensor in sensors {
-					self.update_sensor_metrics_internal(&sensor);
+				for sensor in &sensors {
+					self.update_sensor_metrics_internal(sensor);
 				}
+
+				let seen: HashSet<&str> = sensors.iter().map(|s| s.id.as_str()).collect();
+				handle_missing_sensors(&seen, self.sensor_missing_behavior);
 			}
+			Err(e) if !self.check_installed(&e) => return Ok(()),
 			Err(e) => {
-				error!("Failed to fetch sensors: {}", e);
+				error!("Failed to fetch sensors: {:#}", e);
 				success = false;
 			}
 		}
@@ -45,6 +126,66 @@ impl ProtectClient {
 		Ok(())
 	}
 
+	pub async fn poll_system(&self) -> Result<()> {
+		if !self.should_poll() {
+			return Ok(());
+		}
+
+		let start = Instant::now();
+		let mut success = true;
+
+		match self.get_nvr().await {
+			Ok(nvr) => {
+				self.mark_installed();
+
+				// Connected device count is derived from the cameras attached to this NVR
+				let connected_devices = match self.get_cameras().await {
+					Ok(cameras) => Some(
+						cameras
+							.iter()
+							.filter(|c| protect_state(&c.state) == 1)
+							.count() as f64,
+					),
+					Err(e) => {
+						error!("Failed to fetch cameras: {:#}", e);
+						success = false;
+						None
+					}
+				};
+
+				let cpu = nvr.system_info.as_ref().and_then(|i| i.cpu.as_ref());
+				let memory = nvr.system_info.as_ref().and_then(|i| i.memory.as_ref());
+
+				let memory_usage = memory.and_then(|m| match (m.total, m.available.or(m.free)) {
+					(Some(total), Some(available)) if total > 0.0 => {
+						Some((total - available) / total * 100.0)
+					}
+					_ => None,
+				});
+
+				update_nvr_metrics(NvrMetrics {
+					nvr_id: nvr.id.clone(),
+					nvr_name: nvr.name.clone().unwrap_or_else(|| nvr.id.clone()),
+					nvr_model: nvr.model_key.clone(),
+					cpu_usage: cpu.and_then(|c| c.average_load),
+					memory_usage,
+					temperature: cpu.and_then(|c| c.temperature),
+					connected_devices,
+				});
+			}
+			Err(e) if !self.check_installed(&e) => return Ok(()),
+			Err(e) => {
+				error!("Failed to fetch NVR: {:#}", e);
+				success = false;
+			}
+		}
+
+		let duration = start.elapsed().as_secs_f64();
+		update_poll_metrics("protect_system", success, duration);
+
+		Ok(())
+	}
+
 	fn update_sensor_metrics_internal(&self, sensor: &Sensor) {
 		let mount_type = sensor.mount_type.as_deref().unwrap_or("unknown");
 
@@ -53,15 +194,10 @@ impl ProtectClient {
 			sensor.name, sensor.id
 		);
 
-		// Determine sensor state
-		let state_value = if sensor.state.to_uppercase() == "CONNECTED" {
-			1
-		} else {
-			0
-		};
-
 		// Extract battery percentage
 		let battery_pct = sensor.battery_status.as_ref().and_then(|b| b.percentage);
+		let battery_low = sensor.battery_status.as_ref().and_then(|b| b.is_low);
+		let battery_voltage = sensor.battery_status.as_ref().and_then(|b| b.voltage);
 
 		// Extract sensor stats
 		let (temperature, humidity, light) = if let Some(stats) = &sensor.stats {
@@ -78,19 +214,76 @@ impl ProtectClient {
 		let motion_detected = sensor.is_motion_detected.map(|b| if b { 1 } else { 0 });
 		let is_opened = sensor.is_opened.map(|b| if b { 1 } else { 0 });
 
+		// Detection settings
+		let motion_enabled = sensor
+			.motion_settings
+			.as_ref()
+			.and_then(|s| s.is_enabled)
+			.map(|b| if b { 1 } else { 0 });
+		let alarm_enabled = sensor
+			.alarm_settings
+			.as_ref()
+			.and_then(|s| s.is_enabled)
+			.map(|b| if b { 1 } else { 0 });
+		let leak_enabled = sensor.leak_settings.as_ref().and_then(|s| {
+			match (s.is_internal_enabled, s.is_external_enabled) {
+				(None, None) => None,
+				(internal, external) => Some(if internal.unwrap_or(false) || external.unwrap_or(false) {
+					1
+				} else {
+					0
+				}),
+			}
+		});
+
+		// Detection events, active while their last occurrence is recent
+		let now_ms = Utc::now().timestamp_millis();
+		let is_recent =
+			|at: Option<i64>| at.is_some_and(|at| now_ms - at <= SENSOR_EVENT_WINDOW_SECS * 1000);
+		let as_flag = |active: bool| if active { 1 } else { 0 };
+		let leak_detected = sensor.leak_settings.as_ref().map(|_| {
+			as_flag(is_recent(sensor.leak_detected_at) || is_recent(sensor.external_leak_detected_at))
+		});
+		let alarm_triggered = sensor
+			.alarm_settings
+			.as_ref()
+			.map(|_| as_flag(is_recent(sensor.alarm_triggered_at)));
+		let tampering_detected = Some(as_flag(is_recent(sensor.tampering_detected_at)));
+
 		// Update all metrics
-		update_sensor_metrics(
-			&sensor.id,
-			&sensor.name,
-			mount_type,
+		update_sensor_metrics(SensorMetrics {
+			sensor_id: sensor.id.clone(),
+			sensor_name: sensor.name.clone(),
+			mount_type: mount_type.to_string(),
 			temperature,
 			humidity,
 			light,
-			battery_pct,
-			state_value,
+			battery: battery_pct,
+			battery_low,
+			battery_voltage,
+			state: protect_state(&sensor.state),
+			state_raw: sensor.state.clone(),
 			motion_detected,
 			is_opened,
-		);
+			motion_enabled,
+			alarm_enabled,
+			leak_enabled,
+			leak_detected,
+			tampering_detected,
+			alarm_triggered,
+			last_seen: Instant::now(),
+			extra_labels: self
+				.sensor_metadata
+				.get(&sensor.id)
+				.map(|labels| labels.clone().into_iter().collect())
+				.unwrap_or_default(),
+		});
+	}
+
+	// Fetch an endpoint without parsing it, used by --dump-api
+	pub async fn fetch_raw(&self, path: &str) -> Result<String> {
+		let url = format!("{}{}", self.base_url, path);
+		fetch_raw(&self.client, &url, &self.api_token, "UniFi Protect API").await
 	}
 
 	async fn get_sensors(&self) -> Result<Vec<Sensor>> {
@@ -107,6 +300,10 @@ impl ProtectClient {
 			.await
 			.context("Failed to send request to UniFi Protect API")?;
 
+		if response.status() == StatusCode::NOT_FOUND {
+			return Err(NotInstalled("Protect").into());
+		}
+
 		if !response.status().is_success() {
 			let status = response.status();
 			let error_text = response.text().await.unwrap_or_default();
@@ -131,8 +328,7 @@ impl ProtectClient {
 			anyhow::bail!("UniFi Protect API error ({}): {}", status, error_text);
 		}
 
-		let sensors = response
-			.json::<Vec<Sensor>>()
+		let sensors = read_json::<Vec<Sensor>>(response)
 			.await
 			.context("Failed to parse sensors response")?;
 
@@ -140,4 +336,119 @@ impl ProtectClient {
 
 		Ok(sensors)
 	}
+
+	async fn get_nvr(&self) -> Result<Nvr> {
+		let url = format!("{}/nvrs", self.base_url);
+
+		debug!("Fetching NVR from: {}", url);
+
+		let response = self
+			.client
+			.get(&url)
+			.header("X-API-KEY", &self.api_token)
+			.header("Accept", "application/json")
+			.send()
+			.await
+			.context("Failed to send request to UniFi Protect API")?;
+
+		if response.status() == StatusCode::NOT_FOUND {
+			return Err(NotInstalled("Protect").into());
+		}
+
+		if !response.status().is_success() {
+			let status = response.status();
+			let error_text = response.text().await.unwrap_or_default();
+			anyhow::bail!("Failed to get NVR ({}): {}", status, error_text);
+		}
+
+		read_json::<Nvr>(response)
+			.await
+			.context("Failed to parse NVR response")
+	}
+
+	async fn get_cameras(&self) -> Result<Vec<Camera>> {
+		let url = format!("{}/cameras", self.base_url);
+
+		debug!("Fetching cameras from: {}", url);
+
+		let response = self
+			.client
+			.get(&url)
+			.header("X-API-KEY", &self.api_token)
+			.header("Accept", "application/json")
+			.send()
+			.await
+			.context("Failed to send request to UniFi Protect API")?;
+
+		if response.status() == StatusCode::NOT_FOUND {
+			return Err(NotInstalled("Protect").into());
+		}
+
+		if !response.status().is_success() {
+			let status = response.status();
+			let error_text = response.text().await.unwrap_or_default();
+			anyhow::bail!("Failed to get cameras ({}): {}", status, error_text);
+		}
+
+		read_json::<Vec<Camera>>(response)
+			.await
+			.context("Failed to parse cameras response")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::metrics::{is_available, isolated_metrics, poll_status};
+	use axum::Router;
+	use axum::http::StatusCode;
+	use axum::routing::get;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+	use tokio::net::TcpListener;
+
+	#[tokio::test]
+	async fn protect_is_probed_again_after_being_found_missing() {
+		let _metrics = isolated_metrics().await;
+		let installed = Arc::new(AtomicBool::new(false));
+		let requests = Arc::new(AtomicUsize::new(0));
+
+		let app = Router::new().route(
+			"/proxy/protect/integration/v1/sensors",
+			get({
+				let installed = installed.clone();
+				let requests = requests.clone();
+				move || async move {
+					requests.fetch_add(1, Ordering::Relaxed);
+					if installed.load(Ordering::Relaxed) {
+						(StatusCode::OK, "[]")
+					} else {
+						(StatusCode::NOT_FOUND, "")
+					}
+				}
+			}),
+		);
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+		let mut client = ProtectClient::new(Client::new(), &Config::default());
+		client.base_url = format!("http://{}/proxy/protect/integration/v1", address);
+		client.reprobe_interval = Duration::from_millis(100);
+
+		client.poll_sensors().await.unwrap();
+		assert!(!is_available("protect"));
+
+		// Polls within the re-probe interval don't reach the controller
+		installed.store(true, Ordering::Relaxed);
+		client.poll_sensors().await.unwrap();
+		assert_eq!(requests.load(Ordering::Relaxed), 1);
+		assert!(!is_available("protect"));
+
+		tokio::time::sleep(client.reprobe_interval).await;
+		client.poll_sensors().await.unwrap();
+		assert_eq!(requests.load(Ordering::Relaxed), 2);
+		assert!(is_available("protect"));
+		assert!(poll_status("protect").unwrap().success);
+	}
 }
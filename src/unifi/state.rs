@@ -0,0 +1,52 @@
+use log::warn;
+use std::collections::HashSet;
+use std::sync::{Mutex, PoisonError};
+
+// Known Network device states and the value reported by unifi_device_state
+const DEVICE_STATES: &[(&str, i32)] = &[
+	("ONLINE", 1),
+	("OFFLINE", 0),
+	("PENDING_ADOPTION", 0),
+	("ADOPTING", 0),
+	("GETTING_READY", 0),
+	("UPDATING", 0),
+	("DELETING", 0),
+	("CONNECTION_INTERRUPTED", 0),
+	("ISOLATED", 0),
+];
+
+// Known Protect device states, shared by sensors and cameras
+const PROTECT_STATES: &[(&str, i32)] = &[("CONNECTED", 1), ("CONNECTING", 0), ("DISCONNECTED", 0)];
+
+// Unrecognized states that were already logged, so each is only warned about once
+static WARNED_STATES: once_cell::sync::Lazy<Mutex<HashSet<String>>> =
+	once_cell::sync::Lazy::new(|| Mutex::new(HashSet::new()));
+
+pub fn device_state(raw: &str) -> i32 {
+	parse_state("Network device", DEVICE_STATES, raw)
+}
+
+pub fn protect_state(raw: &str) -> i32 {
+	parse_state("Protect device", PROTECT_STATES, raw)
+}
+
+fn parse_state(kind: &str, known: &[(&str, i32)], raw: &str) -> i32 {
+	let normalized = raw.trim().to_uppercase();
+
+	if let Some((_, value)) = known.iter().find(|(state, _)| *state == normalized) {
+		return *value;
+	}
+
+	let newly_seen = WARNED_STATES
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.insert(format!("{}:{}", kind, normalized));
+	if newly_seen {
+		warn!(
+			"Unrecognized {} state '{}', reporting it as 0 (the raw value is exported in the state_raw label)",
+			kind, raw
+		);
+	}
+
+	0
+}